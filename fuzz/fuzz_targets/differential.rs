@@ -0,0 +1,53 @@
+//! Runs the same operations against every `IntSet` and a `BTreeSet` model,
+//! checking the results and the structure's invariants after each one.
+//!
+//! Every operation is two bytes: the low three bits of the first select the
+//! operation and the rest is the length of a range scan, while the second is
+//! the key. The key space is tiny on purpose, so that removals and repeated
+//! insertions actually hit existing keys.
+//!
+//!     cargo +nightly fuzz run differential
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tree_bench;
+
+
+use std::collections::BTreeSet;
+use tree_bench::IntSet;
+use tree_bench::btree::BTree;
+use tree_bench::rbtree::RBTree;
+
+
+fuzz_target!(|data: &[u8]| {
+    check::<BTreeSet<i64>, BTree<i64>>(data);
+    check::<BTreeSet<i64>, RBTree<i64>>(data);
+});
+
+
+fn check<M: IntSet, A: IntSet>(data: &[u8]) {
+    let mut model = M::empty();
+    let mut set = A::empty();
+    for op in data.chunks(2) {
+        let (tag, key) = match *op {
+            [tag, key] => (tag, key as i8 as i64),
+            _ => break,
+        };
+        match tag & 7 {
+            0 | 1 => assert_eq!(set.get(&key), model.get(&key), "get({})", key),
+            2 | 3 => {
+                set.put(key);
+                model.put(key);
+            }
+            4 | 5 => assert_eq!(set.remove(&key), model.remove(&key), "remove({})", key),
+            6 => {
+                let count = (tag >> 3) as usize;
+                assert_eq!(set.scan(&key, count), model.scan(&key, count), "scan({}, {})", key, count);
+            }
+            _ => assert_eq!(set.scan(&i64::MIN, usize::MAX), model.scan(&i64::MIN, usize::MAX), "iter"),
+        }
+        assert_eq!(set.len(), model.len());
+        set.check_invariants();
+    }
+}
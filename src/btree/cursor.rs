@@ -0,0 +1,301 @@
+//! Cursors over a `BTree`. A cursor keeps the path from the root to its
+//! key, as `Range` does, so most steps stay within a leaf. Past the last key
+//! and before the first one there is a ghost position without a key, where
+//! a seek that matches nothing ends up.
+
+
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use compare::Comparator;
+use super::BTree;
+use super::node::{Node, SPLIT};
+
+
+/// The nodes from the root down to the node holding the current key, each
+/// with the index of the child the path goes through, except for the last
+/// one, which has the index of the key. Empty at the ghost position.
+type Stack<T> = Vec<(*mut Node<T>, usize)>;
+
+
+pub struct Cursor<'a, T: 'a> {
+    root: *mut Node<T>,
+    stack: Stack<T>,
+    marker: PhantomData<&'a Node<T>>,
+}
+
+
+/// A cursor that can also insert keys next to the current one and remove
+/// it, along its own path instead of searching the tree again. Splits,
+/// rotations and merges move keys between nodes, and the path is moved
+/// along with them.
+pub struct CursorMut<'a, T: 'a + Default, C: 'a> {
+    tree: &'a mut BTree<T, C>,
+    stack: Stack<T>,
+}
+
+
+impl<'a, T: Default> Cursor<'a, T> {
+    pub(super) fn new(root: &'a Node<T>, stack: Stack<T>) -> Cursor<'a, T> {
+        Cursor { root: root as *const Node<T> as *mut Node<T>, stack, marker: PhantomData }
+    }
+
+    /// The current key, or `None` at the ghost position.
+    pub fn key(&self) -> Option<&'a T> { unsafe { current(&self.stack) } }
+
+    /// Moves to the next key, or from the last key to the ghost position,
+    /// or from the ghost position to the first key.
+    pub fn move_next(&mut self) { unsafe { step_next(self.root, &mut self.stack) } }
+
+    /// Moves to the previous key, the other way round from `move_next`.
+    pub fn move_prev(&mut self) { unsafe { step_prev(self.root, &mut self.stack) } }
+}
+
+
+impl<'a, T: Default, C: Comparator<T>> CursorMut<'a, T, C> {
+    pub(super) fn new(tree: &'a mut BTree<T, C>, stack: Stack<T>) -> CursorMut<'a, T, C> {
+        CursorMut { tree, stack }
+    }
+
+    pub fn key(&self) -> Option<&T> { unsafe { current(&self.stack) } }
+
+    pub fn move_next(&mut self) { unsafe { step_next(&mut self.tree.root, &mut self.stack) } }
+
+    pub fn move_prev(&mut self) { unsafe { step_prev(&mut self.tree.root, &mut self.stack) } }
+
+    /// Inserts `key` right before the current key, or as the last key at
+    /// the ghost position. The cursor stays where it is. Gives the key back
+    /// if it does not belong there in the order of the tree.
+    pub fn insert_before(&mut self, key: T) -> Result<(), T> { self.insert(key, false) }
+
+    /// Inserts `key` right after the current key, or as the first key at
+    /// the ghost position.
+    pub fn insert_after(&mut self, key: T) -> Result<(), T> { self.insert(key, true) }
+
+    /// Removes the current key and moves on to the next one.
+    pub fn remove_current(&mut self) -> Option<T> {
+        self.stack.last()?;
+        let key = unsafe { Node::remove_at_path(&mut self.stack) };
+        self.tree.len -= 1;
+        // The path ends in the gap the key left, just before the next key.
+        unsafe { climb_next(&mut self.stack) };
+        Some(key)
+    }
+
+    /// Extends the path down to the leaf gap next to the current key, and
+    /// inserts `key` there.
+    fn insert(&mut self, key: T, after: bool) -> Result<(), T> {
+        let depth = self.stack.len();
+        let (node, i) = match self.stack.pop() {
+            Some((node, i)) => (node, i + after as usize),
+            None => {
+                let len = self.tree.root.len();
+                (&mut self.tree.root as *mut Node<T>, if after { 0 } else { len })
+            }
+        };
+        unsafe {
+            descend_to_gap(&mut self.stack, node, i, after);
+            let (before, next) = gap_neighbours(&self.stack);
+            let cmp = &self.tree.cmp;
+            if before.is_some_and(|before| !cmp.less(before, &key))
+                || next.is_some_and(|next| !cmp.less(&key, next)) {
+                self.stack.truncate(depth);
+                if let Some(top) = self.stack.last_mut() {
+                    top.1 -= after as usize;
+                }
+                return Err(key);
+            }
+            ::profile::start();
+            // The full nodes at the bottom of the path are the ones that split.
+            let splits = self.stack.iter().rev()
+                .take_while(|&&(node, _)| (*node).is_full())
+                .count();
+            let gap = self.stack.last().unwrap().1;
+            Node::insert_at_path(&self.stack, key, None);
+            self.tree.len += 1;
+            if depth == 0 {
+                self.stack.clear();
+            } else if after {
+                // The current key is the one before the new key.
+                follow_splits(&mut self.stack, gap, splits);
+                climb_prev(&mut self.stack);
+            } else {
+                follow_splits(&mut self.stack, gap + 1, splits);
+                climb_next(&mut self.stack);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// The path to the first key at or after `bound`.
+pub(super) unsafe fn lower_bound<T, Q, C>(root: *mut Node<T>, bound: Bound<&Q>, cmp: &C)
+                                          -> Stack<T>
+    where T: Default + Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let mut stack = Vec::new();
+    let mut node = root;
+    loop {
+        let i = match bound {
+            Bound::Unbounded => 0,
+            Bound::Included(key) | Bound::Excluded(key) => match (*node).insertion_point(key, cmp) {
+                Ok(i) => {
+                    stack.push((node, i));
+                    if let Bound::Excluded(_) = bound {
+                        step_next(root, &mut stack);
+                    }
+                    return stack;
+                }
+                Err(i) => i,
+            },
+        };
+        stack.push((node, i));
+        match (*node).child_ptr(i) {
+            Some(child) => node = child,
+            None => {
+                climb_next(&mut stack);
+                return stack;
+            }
+        }
+    }
+}
+
+
+/// The path to the last key at or before `bound`.
+pub(super) unsafe fn upper_bound<T, Q, C>(root: *mut Node<T>, bound: Bound<&Q>, cmp: &C)
+                                          -> Stack<T>
+    where T: Default + Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let mut stack = match bound {
+        Bound::Unbounded => Vec::new(),
+        Bound::Included(key) => lower_bound(root, Bound::Excluded(key), cmp),
+        Bound::Excluded(key) => lower_bound(root, Bound::Included(key), cmp),
+    };
+    step_prev(root, &mut stack);
+    stack
+}
+
+
+unsafe fn current<'a, T: Default>(stack: &Stack<T>) -> Option<&'a T> {
+    stack.last().map(|&(node, i)| (*node).key(i))
+}
+
+
+unsafe fn step_next<T: Default>(root: *mut Node<T>, stack: &mut Stack<T>) {
+    match stack.last_mut() {
+        None => descend_to_gap(stack, root, 0, true),
+        Some(top) => {
+            top.1 += 1;
+            let (node, i) = *top;
+            if let Some(child) = (*node).child_ptr(i) {
+                descend_to_gap(stack, child, 0, true);
+            }
+        }
+    }
+    climb_next(stack);
+}
+
+
+unsafe fn step_prev<T: Default>(root: *mut Node<T>, stack: &mut Stack<T>) {
+    let (node, i) = match stack.last() {
+        None => return descend_last(stack, root),
+        Some(&top) => top,
+    };
+    if let Some(child) = (*node).child_ptr(i) {
+        return descend_last(stack, child);
+    }
+    climb_prev(stack);
+}
+
+
+/// Goes down to the last key of the subtree of `node`.
+unsafe fn descend_last<T: Default>(stack: &mut Stack<T>, node: *mut Node<T>) {
+    let len = (*node).len();
+    descend_to_gap(stack, node, len, false);
+    let top = stack.last_mut().unwrap();
+    if top.1 == 0 {
+        // Only an empty root leaf has no key before its last gap.
+        stack.clear();
+    } else {
+        top.1 -= 1;
+    }
+}
+
+
+/// Pushes `node` with the gap `i`, and below it the first gap of the
+/// subtree after it, if `first`, or else the last gap.
+unsafe fn descend_to_gap<T: Default>(stack: &mut Stack<T>, mut node: *mut Node<T>, mut i: usize,
+                                     first: bool) {
+    loop {
+        stack.push((node, i));
+        match (*node).child_ptr(i) {
+            Some(child) => node = child,
+            None => return,
+        }
+        i = if first { 0 } else { (*node).len() };
+    }
+}
+
+
+/// Moves from a gap past the last key of a node up to the next key, or to
+/// the ghost position.
+unsafe fn climb_next<T: Default>(stack: &mut Stack<T>) {
+    while let Some(&(node, i)) = stack.last() {
+        if i < (*node).len() {
+            return;
+        }
+        stack.pop();
+    }
+}
+
+
+/// Moves from a gap before the first key of a node up to the previous key,
+/// or to the ghost position.
+unsafe fn climb_prev<T: Default>(stack: &mut Stack<T>) {
+    while let Some(top) = stack.last_mut() {
+        if top.1 > 0 {
+            top.1 -= 1;
+            return;
+        }
+        stack.pop();
+    }
+}
+
+
+/// Moves a path to a leaf gap along with an insertion into that leaf that
+/// split the bottom `splits` nodes of the path, and the root as well if
+/// that was all of them. `gap` is the gap to follow, numbered with the new
+/// key in the leaf.
+unsafe fn follow_splits<T: Default>(stack: &mut Stack<T>, mut gap: usize, splits: usize) {
+    let depth = stack.len();
+    let top = depth - splits;
+    for level in (top..depth).rev() {
+        // The node kept its first `SPLIT` keys, the next one moved up
+        // before the new right sibling, and the rest went into that.
+        let right = gap > SPLIT;
+        stack[level].1 = if right { gap - SPLIT - 1 } else { gap };
+        gap = if level > 0 { stack[level - 1].1 } else { 0 } + right as usize;
+    }
+    let top = if top == 0 {
+        // The root moved its keys into a new child, next to the split.
+        stack.insert(0, (stack[0].0, gap));
+        0
+    } else {
+        stack[top - 1].1 = gap;
+        top - 1
+    };
+    for level in top + 1..stack.len() {
+        let (parent, i) = stack[level - 1];
+        stack[level].0 = (*parent).child_ptr(i).unwrap();
+    }
+}
+
+
+/// The keys on either side of the leaf gap the path ends in.
+unsafe fn gap_neighbours<'a, T: Default>(stack: &Stack<T>) -> (Option<&'a T>, Option<&'a T>) {
+    let before = stack.iter().rev().find(|&&(_, i)| i > 0).map(|&(node, i)| (*node).key(i - 1));
+    let after = stack.iter().rev().find(|&&(node, i)| i < (*node).len())
+        .map(|&(node, i)| (*node).key(i));
+    (before, after)
+}
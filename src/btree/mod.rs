@@ -1,57 +1,587 @@
 mod slice_util;
 mod node;
+mod map;
+mod cursor;
+mod persistent;
 
 
-use self::node::Node;
-use super::IntSet;
+use std::borrow::Borrow;
+use std::fmt;
+use std::mem::{replace, size_of, swap};
+use std::ops::{Bound, RangeBounds};
+use self::node::{Node, Insert};
+use super::{IntSet, NearestKeys};
+use compare::{Comparator, Natural};
+use set_ops::{self, Difference, Intersection, SortedSet, SymmetricDifference, Union};
+use key::Key;
+use memory::MemoryUsage;
+
+
+pub use self::cursor::{Cursor, CursorMut};
+pub use self::map::{Entry, Iter, Map, OccupiedEntry, VacantEntry};
+pub use self::node::Range;
+pub use self::persistent::PersistentBTree;
 
 
 const B: usize = 6;
+const MIN_FILL: usize = B / 2;
 
-pub static mut SEARCH_TIME: u64 = 0;
-pub static mut FIXUP_TIME: u64 = 0;
 
+/// A set ordered by the comparator `C`, which defaults to the keys' `Ord`.
 #[derive(Debug)]
-pub struct BTree<T: Copy + Default + Ord + Eq> {
+pub struct BTree<T: Default, C = Natural> {
     root: Node<T>,
+    len: usize,
+    cmp: C,
+}
+
+
+impl<T: Default + Ord> BTree<T> {
+    pub fn new() -> BTree<T> { BTree::with_comparator(Natural) }
 }
 
 
-impl<T: Copy + Default + Ord + Eq + ::std::fmt::Debug> BTree<T> {
-    pub fn new() -> BTree<T> { BTree { root: Node::empty() } }
-    pub fn contains(&self, key: T) -> bool { self.root.contains(key) }
-    pub fn insert(&mut self, key: T) {
-        ::tick();
-        match self.root.insert(key) {
-            None => {},
-            Some((key, right)) => self.root.spill_root(key, right)
+impl<T: Default, C: Comparator<T>> BTree<T, C> {
+    pub fn with_comparator(cmp: C) -> BTree<T, C> { BTree { root: Node::empty(), len: 0, cmp } }
+    pub fn comparator(&self) -> &C { &self.cmp }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// The key in the set that compares equal to `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.root.get(key, &self.cmp)
+    }
+
+    pub fn insert(&mut self, key: T) -> bool {
+        ::profile::start();
+        let inserted = match self.root.insert(key, &self.cmp) {
+            Insert::Found => false,
+            Insert::Fit => true,
+            Insert::Split(key, right) => {
+                self.root.spill_root(key, Box::new(right));
+                ::profile::lap("spill_root");
+                true
+            }
+        };
+        self.len += inserted as usize;
+        inserted
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.take(key).is_some()
+    }
+
+    /// Removes and returns the key in the set that compares equal to `key`.
+    pub fn take<Q>(&mut self, key: &Q) -> Option<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let removed = self.root.remove(key, &self.cmp);
+        self.root.collapse_root();
+        self.len -= removed.is_some() as usize;
+        removed
+    }
+
+    pub fn first(&self) -> Option<&T> { self.root.first() }
+    pub fn last(&self) -> Option<&T> { self.root.last() }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.root.take_first())
+    }
+
+    pub fn pop_last(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.root.take_last())
+    }
+
+    /// The greatest key that is not greater than `key`.
+    pub fn floor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.root.nearest(Bound::Included(key), true, &self.cmp)
+    }
+
+    /// The least key that is not less than `key`.
+    pub fn ceiling<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.root.nearest(Bound::Included(key), false, &self.cmp)
+    }
+
+    /// The greatest key less than `key`.
+    pub fn predecessor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.root.nearest(Bound::Excluded(key), true, &self.cmp)
+    }
+
+    /// The least key greater than `key`.
+    pub fn successor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.root.nearest(Bound::Excluded(key), false, &self.cmp)
+    }
+
+    /// Moves the keys at and after `key` into a new tree. The split itself
+    /// takes logarithmic time, but nodes do not record the sizes of their
+    /// subtrees, so the new lengths come from counting the keys of the
+    /// lower of the two trees. That takes time linear in its size, which
+    /// near the middle is linear in the length of the tree.
+    pub fn split_off<Q>(&mut self, key: &Q) -> BTree<T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q> + Clone
+    {
+        let root = self.root.split_off(key, &self.cmp);
+        let len = self.len;
+        let mut other = BTree { root, len: 0, cmp: self.cmp.clone() };
+        if self.root.height() < other.root.height() {
+            self.len = self.root.key_count();
+            other.len = len - self.len;
+        } else {
+            other.len = other.root.key_count();
+            self.len = len - other.len;
+        }
+        other
+    }
+
+    /// Moves all keys of `other` into this tree. If all keys of one tree
+    /// come before those of the other, the trees are joined in time
+    /// logarithmic in their sizes; otherwise the keys move one by one.
+    pub fn append(&mut self, other: &mut BTree<T, C>) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            swap(&mut self.root, &mut other.root);
+            swap(&mut self.len, &mut other.len);
+            return;
+        }
+        let (first, last) = (self.root.first().unwrap(), self.root.last().unwrap());
+        if self.cmp.less(other.root.last().unwrap(), first) {
+            swap(&mut self.root, &mut other.root);
+        } else if !self.cmp.less(last, other.root.first().unwrap()) {
+            let mut cursor = other.lower_bound_mut::<T>(Bound::Unbounded);
+            while let Some(key) = cursor.remove_current() {
+                self.insert(key);
+            }
+            return;
+        }
+        let key = self.root.take_last();
+        let right = replace(&mut other.root, Node::empty());
+        self.root.join(key, right);
+        self.len += other.len;
+        other.len = 0;
+    }
+
+    /// The keys in order.
+    pub fn iter(&self) -> Range<'_, T> { self.range::<T, _>(..) }
+
+    /// The keys in either tree, in order.
+    pub fn union<'a>(&'a self, other: &'a Self) -> Union<'a, T, Self> { Union::new(self, other) }
+
+    /// The keys in both trees, in order.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> Intersection<'a, T, Self> {
+        Intersection::new(self, other)
+    }
+
+    /// The keys of this tree that are not in `other`, in order.
+    pub fn difference<'a>(&'a self, other: &'a Self) -> Difference<'a, T, Self> {
+        Difference::new(self, other)
+    }
+
+    /// The keys in exactly one of the trees, in order.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self) -> SymmetricDifference<'a, T, Self> {
+        SymmetricDifference::new(self, other)
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool { set_ops::is_subset(self, other) }
+    pub fn is_disjoint(&self, other: &Self) -> bool { set_ops::is_disjoint(self, other) }
+
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>
+    {
+        Range::new(&self.root, range.start_bound(), range.end_bound(), &self.cmp)
+    }
+
+    /// A cursor on the first key at or after `bound`, or on the ghost
+    /// position if there is none.
+    pub fn lower_bound<Q>(&self, bound: Bound<&Q>) -> Cursor<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let root = &self.root as *const Node<T> as *mut Node<T>;
+        Cursor::new(&self.root, unsafe { cursor::lower_bound(root, bound, &self.cmp) })
+    }
+
+    /// A cursor on the last key at or before `bound`.
+    pub fn upper_bound<Q>(&self, bound: Bound<&Q>) -> Cursor<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let root = &self.root as *const Node<T> as *mut Node<T>;
+        Cursor::new(&self.root, unsafe { cursor::upper_bound(root, bound, &self.cmp) })
+    }
+
+    pub fn lower_bound_mut<Q>(&mut self, bound: Bound<&Q>) -> CursorMut<'_, T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let stack = unsafe { cursor::lower_bound(&mut self.root, bound, &self.cmp) };
+        CursorMut::new(self, stack)
+    }
+
+    pub fn upper_bound_mut<Q>(&mut self, bound: Bound<&Q>) -> CursorMut<'_, T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let stack = unsafe { cursor::upper_bound(&mut self.root, bound, &self.cmp) };
+        CursorMut::new(self, stack)
+    }
+
+    /// The tree in Graphviz format.
+    pub fn to_dot(&self) -> String where T: fmt::Display {
+        let mut result = String::from("digraph BTree {\nnode [shape=record];\n");
+        self.root.graph_vis(&mut 0, &mut result);
+        result + "}\n"
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        self.root.collect_stats(0, &mut stats);
+        stats
+    }
+
+    pub fn check_invariants(&self) where T: fmt::Debug {
+        let keys = self.root.check_invariants(None, None, 0, &mut None, &self.cmp);
+        assert_eq!(keys, self.len, "Wrong length");
+    }
+
+    /// Every node has room for `B` keys and `B + 1` children, whether it
+    /// is a leaf or not. The root node lives in the `BTree` itself.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let nodes = self.root.node_count();
+        MemoryUsage {
+            nodes,
+            node_size: size_of::<Node<T>>(),
+            total: (nodes - 1) * size_of::<Node<T>>() + size_of::<Self>(),
+            keys: self.len * size_of::<T>(),
         }
-        ::tack(unsafe { &mut FIXUP_TIME })
     }
 }
 
 
-impl IntSet for BTree<i64> {
+/// The shape of the tree. Level 0 is the root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub nodes_per_level: Vec<usize>,
+    /// The number of nodes holding `i` keys, for `i` in `0..=B`.
+    pub fill_histogram: [usize; B + 1],
+    pub leaves: usize,
+    pub internal: usize,
+}
+
+
+impl Stats {
+    pub fn height(&self) -> usize { self.nodes_per_level.len() }
+
+    /// The fraction of key slots that are in use.
+    pub fn occupancy(&self) -> f64 {
+        let keys: usize = self.fill_histogram.iter().enumerate().map(|(fill, &n)| fill * n).sum();
+        keys as f64 / ((self.leaves + self.internal) * B).max(1) as f64
+    }
+}
+
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "height {}, {} leaves, {} internal, {:.1}% occupancy",
+                 self.height(), self.leaves, self.internal, 100.0 * self.occupancy())?;
+        write!(f, "        nodes per level {:?}, fill histogram {:?}",
+               self.nodes_per_level, self.fill_histogram)
+    }
+}
+
+
+impl<T: Default, C: Comparator<T>> SortedSet<T> for BTree<T, C> {
+    type Cmp = C;
+    type Iter<'a> = Range<'a, T> where T: 'a, C: 'a;
+
+    fn len(&self) -> usize { self.len }
+    fn iter(&self) -> Range<'_, T> { BTree::iter(self) }
+    fn get(&self, key: &T) -> Option<&T> { BTree::get(self, key) }
+    fn comparator(&self) -> &C { &self.cmp }
+}
+
+
+impl<T: Default, C: Comparator<T> + Default> Default for BTree<T, C> {
+    fn default() -> Self { BTree::with_comparator(C::default()) }
+}
+
+
+impl<K: Key> IntSet<K> for BTree<K> {
     fn empty() -> Self { BTree::new() }
 
-    fn get(&self, key: i64) -> bool { self.contains(key) }
+    fn get(&self, key: &K) -> bool { self.contains(key) }
 
-    fn put(&mut self, key: i64) { self.insert(key) }
+    fn put(&mut self, key: K) { self.insert(key); }
 
-    fn report(){
-        unsafe {
-            println!("Search: {} µs\nFixup:  {} µs\n", SEARCH_TIME / 1000, FIXUP_TIME / 1000);
-            SEARCH_TIME = 0;
-            FIXUP_TIME = 0;
-        }
+    fn len(&self) -> usize { self.len() }
+
+    fn remove(&mut self, key: &K) -> bool { BTree::remove(self, key) }
+
+    fn scan(&self, from: &K, count: usize) -> i64 {
+        self.range(from..).take(count).fold(0, |acc, key| acc.wrapping_add(key.checksum()))
     }
+
+    fn memory_usage(&self) -> Option<MemoryUsage> { Some(self.memory_usage()) }
+
+    fn report(&self) {
+        ::profile::report();
+        println!("Shape:  {}\n", self.stats());
+    }
+
+    fn check_invariants(&self) { BTree::check_invariants(self) }
+}
+
+
+impl<K: Key> NearestKeys<K> for BTree<K> {
+    fn first(&self) -> Option<&K> { BTree::first(self) }
+
+    fn last(&self) -> Option<&K> { BTree::last(self) }
+
+    fn floor(&self, key: &K) -> Option<&K> { BTree::floor(self, key) }
+
+    fn ceiling(&self, key: &K) -> Option<&K> { BTree::ceiling(self, key) }
+
+    fn predecessor(&self, key: &K) -> Option<&K> { BTree::predecessor(self, key) }
+
+    fn successor(&self, key: &K) -> Option<&K> { BTree::successor(self, key) }
+
+    fn pop_first(&mut self) -> Option<K> { BTree::pop_first(self) }
+
+    fn pop_last(&mut self) -> Option<K> { BTree::pop_last(self) }
 }
 
 
 #[test]
 fn btree_vs_btreeset() {
     use std::collections::BTreeSet;
-    super::compare_test::<BTreeSet<i64>, BTree<i64>>();
+    ::test_util::compare_test::<BTreeSet<i64>, BTree<i64>>();
+}
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn btree_matches_model(ops in ::test_util::arb_ops()) {
+        use std::collections::BTreeSet;
+        ::test_util::compare_ops::<BTreeSet<i64>, BTree<i64>>(&ops);
+    }
+
+    #[test]
+    fn btree_range_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..200),
+                               start in ::test_util::arb_bound(), end in ::test_util::arb_bound()) {
+        let mut tree = BTree::new();
+        for &key in &keys {
+            tree.insert(key);
+        }
+        let range: Vec<i64> = tree.range((start, end)).cloned().collect();
+        prop_assert_eq!(range, ::test_util::range_model(&keys, (start, end)));
+    }
+
+    #[test]
+    fn btree_split_off_and_append(keys in ::proptest::collection::vec(-500i64..500, 0..600),
+                                  other in ::proptest::collection::vec(-500i64..500, 0..100),
+                                  at in -550i64..550, flip in ::proptest::bool::ANY) {
+        ::test_util::split_off_and_append::<BTree<i64>>(&keys, &other, at, flip)?;
+    }
+
+    #[test]
+    fn btree_nearest_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..200),
+                                   probes in ::proptest::collection::vec(-60i64..60, 0..20),
+                                   pops in ::proptest::collection::vec(::proptest::bool::ANY, 0..250)) {
+        ::test_util::nearest_matches_model::<BTree<i64>>(&keys, &probes, &pops)?;
+    }
+
+    #[test]
+    fn btree_cursor_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..100),
+                                  bound in ::test_util::arb_bound(),
+                                  upper in ::proptest::bool::ANY,
+                                  ops in ::test_util::arb_cursor_ops()) {
+        ::test_util::cursor_matches_model::<BTree<i64>>(&keys, bound, upper, ops)?;
+    }
+}
+
+
+#[test]
+fn btree_range() {
+    use std::ops::Bound::Included;
+    let mut tree = BTree::new();
+    for i in 0..1000 {
+        tree.insert(i * 2);
+    }
+    assert_eq!(tree.len(), 1000);
+    assert!(tree.range(..).cloned().eq((0..1000).map(|i| i * 2)));
+    assert!(tree.range(10..20).cloned().eq(vec![10, 12, 14, 16, 18]));
+    assert!(tree.range(11..=20).cloned().eq(vec![12, 14, 16, 18, 20]));
+    assert!(tree.range(1990..).cloned().eq(vec![1990, 1992, 1994, 1996, 1998]));
+    assert_eq!(tree.range(5000..).next(), None);
+    assert_eq!(tree.range((Included(5000), Included(10))).next(), None);
+}
+
+
+#[test]
+fn btree_memory_usage() {
+    let mut tree = BTree::new();
+    assert_eq!(tree.memory_usage().nodes, 1);
+    for i in 0..B as i64 {
+        tree.insert(i);
+    }
+    assert_eq!(tree.memory_usage().nodes, 1);
+    tree.insert(B as i64);
+    let usage = tree.memory_usage();
+    assert_eq!(usage.nodes, 3);
+    assert_eq!(usage.keys, (B + 1) * size_of::<i64>());
+    assert_eq!(usage.total, 2 * usage.node_size + size_of::<BTree<i64>>());
 }
 
 
+#[test]
+fn btree_stats() {
+    let mut sorted = BTree::new();
+    let mut random = BTree::new();
+    for i in 0..10_000 {
+        sorted.insert(i);
+        random.insert((i * 7919) % 10_000);
+    }
+    for tree in &[&sorted, &random] {
+        let stats = tree.stats();
+        assert_eq!(stats.nodes_per_level[0], 1);
+        assert_eq!(*stats.nodes_per_level.last().unwrap(), stats.leaves);
+        assert_eq!(stats.nodes_per_level.iter().sum::<usize>(), tree.memory_usage().nodes);
+        assert!(stats.fill_histogram[..MIN_FILL].iter().sum::<usize>() <= 1);
+    }
+    // Splitting in half leaves every node but the last one on each level
+    // half empty when keys only ever go to the right.
+    let sorted = sorted.stats();
+    assert!(sorted.occupancy() < 0.55);
+    assert!(random.stats().occupancy() > sorted.occupancy());
+}
+
+
+#[test]
+fn btree_to_dot() {
+    let mut tree = BTree::new();
+    for i in 0..B as i64 + 1 {
+        tree.insert(i);
+    }
+    let dot = tree.to_dot();
+    assert!(dot.starts_with("digraph BTree {\n"));
+    assert!(dot.contains("n0 [label=\"<c0>|3|<c1>\"];"));
+    assert!(dot.contains("n1 [label=\"<c0>|0|<c1>|1|<c2>|2|<c3>\"];"));
+    assert!(dot.contains("n0:c0 -> n1;"));
+    assert!(dot.contains("n0:c1 -> n2;"));
+
+    let mut tree = BTree::new();
+    tree.insert("{a|b} \"c\"".to_string());
+    assert!(tree.to_dot().contains("n0 [label=\"<c0>|\\{a\\|b\\}\\ \\\"c\\\"|<c1>\"];"));
+}
+
+
+#[test]
+fn btree_remove_all() {
+    let mut tree = BTree::new();
+    for i in 0..1000 {
+        tree.insert((i * 7919) % 1000);
+    }
+    tree.check_invariants();
+    for i in 0..1000 {
+        assert!(tree.remove(&((i * 104_729) % 1000)));
+        assert!(!tree.contains(&((i * 104_729) % 1000)));
+        tree.check_invariants();
+    }
+    assert_eq!(tree.len(), 0);
+    assert_eq!(tree.range(..).next(), None);
+}
+
+
+#[test]
+fn btree_custom_comparator() {
+    use compare::Reverse;
+    use std::ops::Bound;
+    let mut tree = BTree::with_comparator(Reverse(Natural));
+    for i in 0..100 {
+        tree.insert((i * 37) % 100);
+    }
+    tree.check_invariants();
+    assert!(tree.range(..).cloned().eq((0..100).rev()));
+    // In reverse order, 20 comes before 10.
+    let (from, to) = (Bound::Included(20), Bound::Included(10));
+    assert!(tree.range((from, to)).cloned().eq((10..21).rev()));
+    assert!(tree.remove(&50));
+    assert!(!tree.contains(&50));
+
+    let mut tree = BTree::with_comparator(|a: &String, b: &String| {
+        a.to_lowercase().cmp(&b.to_lowercase())
+    });
+    for word in &["b", "A", "c", "B", "a"] {
+        tree.insert(word.to_string());
+    }
+    tree.check_invariants();
+    assert_eq!(tree.len(), 3);
+    assert!(tree.contains(&"C".to_string()));
+    assert!(tree.range(..).map(String::as_str).eq(vec!["A", "b", "c"]));
+}
+
+
+#[test]
+fn btree_borrowed_lookups() {
+    use std::ops::Bound::*;
+    let mut tree = BTree::new();
+    for word in &["apple", "banana", "cherry", "date"] {
+        tree.insert(word.to_string());
+    }
+    assert!(tree.contains("banana"));
+    assert!(!tree.contains("fig"));
+    assert_eq!(tree.get("cherry").map(String::as_str), Some("cherry"));
+    assert_eq!(tree.get("fig"), None);
+    // `str` is unsized, so its ranges are pairs of bounds.
+    let range = |from, to| tree.range::<str, _>((Included(from), Excluded(to)));
+    assert!(range("b", "d").map(String::as_str).eq(vec!["banana", "cherry"]));
+    assert_eq!(range("d", "b").next(), None);
+    assert!(tree.range::<str, _>((Excluded("banana"), Unbounded)).map(String::as_str)
+            .eq(vec!["cherry", "date"]));
+    assert!(tree.remove("apple"));
+    assert!(!tree.remove("apple"));
+    assert_eq!(tree.len(), 3);
+    tree.check_invariants();
+}
+
+
+#[test]
+fn btree_cursor() {
+    ::test_util::cursor_test::<BTree<i64>>();
+}
+
+
+#[test]
+fn btree_shards() {
+    ::test_util::shards_test::<BTree<i64>>();
+}
+
+
+#[test]
+fn btree_split_off_compares_along_a_path() {
+    ::test_util::split_off_compares_along_a_path::<BTree<i64, ::test_util::Counting>>();
+}
@@ -1,18 +1,130 @@
-use super::B;
-use std::mem::swap;
+use super::{B, MIN_FILL};
+use std::borrow::Borrow;
+use std::mem::{replace, swap, take};
 use std::cmp::Ordering;
+use std::ops::Bound;
+use std::ptr;
 use super::slice_util::*;
+use compare::Comparator;
+#[cfg(test)]
+use compare::Natural;
 
 
 #[derive(Debug)]
-pub struct Node<T: Copy> {
+pub struct Node<T> {
     fill: u8,
     values: [T; B],
     children: Option<[Option<Box<Node<T>>>; B + 1]>
 }
 
 
-impl<T: Copy + Eq + Ord + Default> Node<T> {
+pub enum Insert<T> {
+    Found,
+    Fit,
+    Split(T, Node<T>),
+}
+
+
+/// The nodes from the root down to a leaf, each with the index of the gap
+/// between its keys that the search went through.
+pub struct Path<T>(pub(super) Vec<(*mut Node<T>, usize)>);
+
+
+/// A tree of height `h` holds at least `2 (MIN_FILL + 1)^(h - 1) - 1` keys,
+/// so no tree that fits in memory is higher.
+const MAX_HEIGHT: usize = (usize::BITS / (MIN_FILL as u32 + 1).ilog2()) as usize + 1;
+
+
+/// The children of a node taken apart by `split_off`.
+type Children<T> = Vec<Box<Node<T>>>;
+
+
+/// The keys a full node keeps when an insertion splits it. The next key
+/// moves up as the median and the rest go to the new right sibling.
+pub(super) const SPLIT: usize = B.div_ceil(2);
+
+
+/// How `fix_child` refilled a child, by moving keys across the separator
+/// `sep`, with the fill the child before it had until then.
+pub(super) enum Rebalance {
+    RotateRight { sep: usize, lfill: usize },
+    RotateLeft { sep: usize, lfill: usize },
+    Merge { sep: usize, lfill: usize },
+}
+
+
+/// Where a key went when the node it was inserted into split: a position
+/// in the node itself, the median that moves up, or a position in the new
+/// right sibling.
+enum Side {
+    Left(usize),
+    Mid,
+    Right(usize),
+}
+
+
+impl Side {
+    fn of(i: usize) -> Side {
+        if i < SPLIT {
+            Side::Left(i)
+        } else if i == SPLIT {
+            Side::Mid
+        } else {
+            Side::Right(i - SPLIT - 1)
+        }
+    }
+}
+
+
+impl Rebalance {
+    /// Moves a position in the rebalanced node, the `c`th child and the
+    /// `j`th gap or child in it, along with the keys around it. Gaps and
+    /// children are numbered alike, so both move the same way.
+    fn follow(&self, c: &mut usize, j: &mut usize) {
+        match *self {
+            Rebalance::RotateRight { sep, lfill } => {
+                if *c == sep + 1 {
+                    *j += 1;
+                } else if *c == sep && *j == lfill {
+                    *c = sep + 1;
+                    *j = 0;
+                }
+            }
+            Rebalance::RotateLeft { sep, lfill } => {
+                if *c == sep + 1 && *j == 0 {
+                    *c = sep;
+                    *j = lfill + 1;
+                } else if *c == sep + 1 {
+                    *j -= 1;
+                }
+            }
+            Rebalance::Merge { sep, lfill } => {
+                if *c == sep + 1 {
+                    *c = sep;
+                    *j += lfill + 1;
+                } else if *c > sep + 1 {
+                    *c -= 1;
+                }
+            }
+        }
+    }
+}
+
+
+/// Moves the path along with the rebalancing of the node at `level`.
+unsafe fn follow<T: Default>(path: &mut [(*mut Node<T>, usize)], level: usize,
+                             fix: Option<Rebalance>) {
+    if let Some(fix) = fix {
+        let (parent, mut c) = path[level];
+        let mut j = path[level + 1].1;
+        fix.follow(&mut c, &mut j);
+        path[level].1 = c;
+        path[level + 1] = ((*parent).child_ptr(c).unwrap(), j);
+    }
+}
+
+
+impl<T: Default> Node<T> {
     pub fn empty() -> Node<T> {
         Node {
             fill: 0,
@@ -21,7 +133,7 @@ impl<T: Copy + Eq + Ord + Default> Node<T> {
         }
     }
 
-    pub fn spill_root(&mut self, key: T, node: Node<T>) {
+    pub fn spill_root(&mut self, key: T, node: Box<Node<T>>) {
         let mut values: [T; B] = Default::default();
         values[0] = key;
         swap(&mut values, &mut self.values);
@@ -33,50 +145,100 @@ impl<T: Copy + Eq + Ord + Default> Node<T> {
         swap(&mut fill, &mut self.fill);
 
         self.children.as_mut().unwrap()[0] = Some(Box::new(Node {
-            fill: fill,
-            values: values,
-            children: children,
+            fill,
+            values,
+            children,
         }));
 
-        self.children.as_mut().unwrap()[1] = Some(Box::new(node));
+        self.children.as_mut().unwrap()[1] = Some(node);
     }
 
-    pub fn contains(&self, key: T) -> bool {
-        match self.insertion_point(key) {
-            Ok(_) => true,
+    /// Inverse of `spill_root`: replaces an empty internal root with its only child.
+    pub fn collapse_root(&mut self) {
+        if self.fill > 0 || self.children.is_none() {
+            return;
+        }
+        let child = take(&mut self.children.as_mut().unwrap()[0]).unwrap();
+        *self = *child;
+    }
+
+    pub fn get<Q, C>(&self, key: &Q, cmp: &C) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        match self.insertion_point(key, cmp) {
+            Ok(i) => Some(&self.values[i]),
             Err(ip) => match self.children {
-                None => false,
+                None => None,
                 Some(ref children) => {
                     let child = unsafe { children.get_unchecked(ip) };
-                    child.as_ref().unwrap().contains(key)
+                    child.as_ref().unwrap().get(key, cmp)
                 },
             },
         }
     }
 
-    pub fn insert(&mut self, key: T) -> Option<(T, Node<T>)> {
-        let insertion_point = match self.insertion_point(key) {
-            Ok(_) => {
-                ::tack(unsafe {&mut super::SEARCH_TIME});
-                ::tick();
-                return None
-            },
+    /// The first key after `bound`, or with `before`, the last key before
+    /// it. The closest key seen so far is in the node above the gap the
+    /// descent takes, so it only changes on the way down.
+    pub fn nearest<Q, C>(&self, bound: Bound<&Q>, before: bool, cmp: &C) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut node = self;
+        let mut nearest = None;
+        loop {
+            let gap = match bound {
+                Bound::Unbounded if before => node.fill as usize,
+                Bound::Unbounded => 0,
+                Bound::Included(key) | Bound::Excluded(key) => match node.insertion_point(key, cmp) {
+                    Ok(i) if matches!(bound, Bound::Included(_)) => return Some(&node.values[i]),
+                    Ok(i) => i + !before as usize,
+                    Err(i) => i,
+                },
+            };
+            if before && gap > 0 {
+                nearest = Some(&node.values[gap - 1]);
+            } else if !before && gap < node.fill as usize {
+                nearest = Some(&node.values[gap]);
+            }
+            match node.child(gap) {
+                Some(child) => node = child,
+                None => return nearest,
+            }
+        }
+    }
+
+    pub fn insert<C: Comparator<T>>(&mut self, key: T, cmp: &C) -> Insert<T> {
+        self.insert_counting_splits(key, cmp, &mut 0)
+    }
+
+    /// Splits happen from the leaf upwards, so the number of nodes split so
+    /// far is also the level of the next split, counting leaves as level 0.
+    fn insert_counting_splits<C: Comparator<T>>(&mut self, key: T, cmp: &C, splits: &mut usize)
+                                                -> Insert<T> {
+        let insertion_point = self.insertion_point(&key, cmp);
+        ::profile::lap("insertion_point");
+        let insertion_point = match insertion_point {
+            Ok(_) => return Insert::Found,
             Err(i) => i,
         };
 
         let (key, right) = if let Some(ref mut children) = self.children {
             // let child = &mut children[insertion_point];
             let child = unsafe { children.get_unchecked_mut(insertion_point) };
-            match child.as_mut().unwrap().insert(key) {
-                None => return None,
-                Some((key, right)) => (key, Some(Box::new(right)))
+            match child.as_mut().unwrap().insert_counting_splits(key, cmp, splits) {
+                Insert::Split(key, right) => (key, Some(Box::new(right))),
+                done => return done,
             }
         } else {
-            ::tack(unsafe {&mut super::SEARCH_TIME});
-            ::tick();
             (key, None)
         };
+        self.insert_at(insertion_point, key, right, splits)
+    }
 
+    /// Puts `key` at `insertion_point`, with `right` as the child after it in
+    /// an internal node, splitting the node if it is full.
+    fn insert_at(&mut self, insertion_point: usize, key: T, right: Option<Box<Node<T>>>,
+                 splits: &mut usize) -> Insert<T> {
         if !self.is_full() {
             shift(&mut self.values[insertion_point..], key);
             if let Some(right) = right {
@@ -85,19 +247,24 @@ impl<T: Copy + Eq + Ord + Default> Node<T> {
                 shift(&mut children[insertion_point + 1..], Some(right));
             }
             self.fill += 1;
-            return None
+            ::profile::lap("shift");
+            return Insert::Fit
         }
 
+        ::profile::count("splits per level", *splits);
+        *splits += 1;
         let ((lvalues, lfill),
             mid,
-            (rvalues, rfill)) = insert_split_spill(&self.values, insertion_point, key);
+            (rvalues, rfill)) = insert_split_spill(&mut self.values, insertion_point, key);
         self.values = lvalues;
         self.fill = lfill;
+        ::profile::lap("insert_split_spill");
 
         let rchildren = if let Some(right) = right {
             let children = self.children.as_mut().unwrap();
             let (lchildren, rchildren) = insert_split(children, insertion_point + 1, Some(right));
             *children = lchildren;
+            ::profile::lap("insert_split");
             Some(rchildren)
         } else {
             None
@@ -109,26 +276,718 @@ impl<T: Copy + Eq + Ord + Default> Node<T> {
             children: rchildren,
         };
 
-        Some((mid, right))
+        Insert::Split(mid, right)
+    }
+
+    pub fn get_mut<Q, C>(&mut self, key: &Q, cmp: &C) -> Option<&mut T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        match self.insertion_point(key, cmp) {
+            Ok(i) => Some(&mut self.values[i]),
+            Err(i) => match self.children {
+                None => None,
+                Some(ref mut children) => children[i].as_mut().unwrap().get_mut(key, cmp),
+            },
+        }
     }
 
+    /// Finds the key equal to `key`, or the path to the leaf position where
+    /// it would be inserted. The descent is kept on the stack, so that only
+    /// a miss allocates.
+    pub fn search<Q, C>(&mut self, key: &Q, cmp: &C) -> Result<*mut T, Path<T>>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut steps = [(ptr::null_mut(), 0); MAX_HEIGHT];
+        let mut node: *mut Node<T> = self;
+        let mut depth = 0;
+        loop {
+            let node_ref = unsafe { &mut *node };
+            let i = match node_ref.insertion_point(key, cmp) {
+                Ok(i) => return Ok(&mut node_ref.values[i]),
+                Err(i) => i,
+            };
+            steps[depth] = (node, i);
+            match node_ref.children {
+                None => return Err(Path(steps[..=depth].to_vec())),
+                Some(ref mut children) => node = &mut **children[i].as_mut().unwrap(),
+            }
+            depth += 1;
+        }
+    }
+
+    /// Inserts `key` where `search` stopped, splitting nodes up the path and
+    /// the root as needed. In an internal node, `right` becomes the child
+    /// after the key. Returns the key's final place, and whether any node
+    /// split, which moves other keys too.
+    ///
+    /// The nodes on the path must not have changed since the search.
+    pub unsafe fn insert_at_path(path: &[(*mut Node<T>, usize)], key: T,
+                                 right: Option<Box<Node<T>>>) -> (*mut T, bool) {
+        let mut key = key;
+        let mut right = right;
+        // Set once the inserted key has settled, rather than moving up as
+        // the median of a split.
+        let mut result: Option<*mut T> = None;
+        let mut root_side = None;
+        let mut splits = 0;
+        for (level, &(node, i)) in path.iter().enumerate().rev() {
+            let (mid, mut split) = match (*node).insert_at(i, key, right, &mut splits) {
+                Insert::Split(mid, split) => (mid, Box::new(split)),
+                _ => return (result.unwrap_or(&mut (*node).values[i]), splits > 0),
+            };
+            if result.is_none() {
+                match Side::of(i) {
+                    Side::Left(j) if level > 0 => result = Some(&mut (*node).values[j]),
+                    // The new node is boxed already, so the key stays put.
+                    Side::Right(j) => result = Some(&mut split.values[j]),
+                    // Either the median moves up, or the key is in the root,
+                    // whose keys are about to move into a new child.
+                    side => if level == 0 {
+                        root_side = Some(side);
+                    },
+                }
+            }
+            key = mid;
+            right = Some(split);
+        }
+        let root = path[0].0;
+        (*root).spill_root(key, right.unwrap());
+        let result = match root_side {
+            Some(Side::Left(j)) => &mut (*root).child_mut(0).values[j],
+            Some(_) => &mut (*root).values[0],
+            None => result.unwrap(),
+        };
+        (result, true)
+    }
+
+    /// Removes `key` from the subtree, returning the removed key. The node
+    /// itself may be left with fewer than `MIN_FILL` values, it is up to the
+    /// parent to rebalance it.
+    pub fn remove<Q, C>(&mut self, key: &Q, cmp: &C) -> Option<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        match self.insertion_point(key, cmp) {
+            Ok(i) => {
+                if self.children.is_none() {
+                    return Some(self.remove_leaf_value(i));
+                }
+                let last = self.child_mut(i).pop_last();
+                let removed = ::std::mem::replace(&mut self.values[i], last);
+                self.fix_child(i);
+                Some(removed)
+            }
+            Err(i) => {
+                self.children.as_ref()?;
+                let removed = self.child_mut(i).remove(key, cmp);
+                if removed.is_some() {
+                    self.fix_child(i);
+                }
+                removed
+            }
+        }
+    }
+
+    /// Removes the key a cursor's path leads to, and rebalances the nodes
+    /// on the path from the bottom up, as `remove` does on its way back.
+    /// The path is left at the leaf gap where the key was, moved along with
+    /// the keys that rotations and merges move, and without the old root if
+    /// that collapsed.
+    pub unsafe fn remove_at_path(path: &mut Vec<(*mut Node<T>, usize)>) -> T {
+        let level = path.len() - 1;
+        let (node, i) = path[level];
+        let removed = if (*node).children.is_none() {
+            (*node).remove_leaf_value(i)
+        } else {
+            let last = (*node).child_mut(i).pop_last();
+            let removed = replace(&mut (*node).values[i], last);
+            // The gap after the predecessor, which took the key's place.
+            path[level].1 = i + 1;
+            let mut child = (*node).child_ptr(i + 1);
+            while let Some(node) = child {
+                path.push((node, 0));
+                child = (*node).child_ptr(0);
+            }
+            let fix = (*node).fix_child(i);
+            follow(path, level, fix);
+            removed
+        };
+        for level in (0..level).rev() {
+            let (parent, i) = path[level];
+            let fix = (*parent).fix_child(i);
+            follow(path, level, fix);
+        }
+        let root = path[0].0;
+        if (*root).fill == 0 && (*root).children.is_some() {
+            (*root).collapse_root();
+            path.remove(0);
+            path[0].0 = root;
+        }
+        removed
+    }
+
+    fn pop_last(&mut self) -> T {
+        let last = self.fill as usize;
+        if self.children.is_none() {
+            return self.remove_leaf_value(last - 1);
+        }
+        let result = self.child_mut(last).pop_last();
+        self.fix_child(last);
+        result
+    }
+
+    fn pop_first(&mut self) -> T {
+        if self.children.is_none() {
+            return self.remove_leaf_value(0);
+        }
+        let result = self.child_mut(0).pop_first();
+        self.fix_child(0);
+        result
+    }
+
+    fn remove_leaf_value(&mut self, i: usize) -> T {
+        let fill = self.fill as usize;
+        self.fill -= 1;
+        unshift(&mut self.values[i..fill])
+    }
+
+    /// Restores `MIN_FILL` for the `i`th child by borrowing a value from
+    /// a sibling, or by merging with a sibling if both are minimal. Returns
+    /// what it did, if there was anything to restore.
+    fn fix_child(&mut self, i: usize) -> Option<Rebalance> {
+        let fill = self.fill as usize;
+        if self.child(i).unwrap().fill as usize >= MIN_FILL {
+            return None;
+        }
+        let sep = if i > 0 { i - 1 } else { i };
+        let lfill = self.child(sep).unwrap().fill as usize;
+        Some(if i > 0 && lfill > MIN_FILL {
+            self.rotate_right(sep);
+            Rebalance::RotateRight { sep, lfill }
+        } else if i < fill && self.child(i + 1).unwrap().fill as usize > MIN_FILL {
+            let lfill = self.child(i).unwrap().fill as usize;
+            self.rotate_left(i);
+            Rebalance::RotateLeft { sep: i, lfill }
+        } else {
+            self.merge(sep);
+            Rebalance::Merge { sep, lfill }
+        })
+    }
+
+    /// Restores `MIN_FILL` for the `i`th child however many keys it lacks,
+    /// as long as its siblings have enough to spare or to merge with.
+    fn refill_child(&mut self, i: usize) {
+        while i <= self.fill as usize && self.fix_child(i).is_some() {}
+    }
+
+    /// Moves the last value of the `sep`th child through the separator into
+    /// the front of the `sep + 1`th child.
+    fn rotate_right(&mut self, sep: usize) {
+        let (left, right) = self.siblings_mut(sep);
+        let (lfill, rfill) = (left.fill as usize, right.fill as usize);
+        let value = take(&mut left.values[lfill - 1]);
+        left.fill -= 1;
+        let value = ::std::mem::replace(&mut self.values[sep], value);
+        let (left, right) = self.siblings_mut(sep);
+        shift(&mut right.values[..rfill + 1], value);
+        right.fill += 1;
+        if let Some(ref mut lchildren) = left.children {
+            let child = take(&mut lchildren[lfill]);
+            shift(&mut right.children.as_mut().unwrap()[..rfill + 2], child);
+        }
+    }
+
+    /// Moves the first value of the `sep + 1`th child through the separator
+    /// into the back of the `sep`th child.
+    fn rotate_left(&mut self, sep: usize) {
+        let (left, right) = self.siblings_mut(sep);
+        let (lfill, rfill) = (left.fill as usize, right.fill as usize);
+        let value = unshift(&mut right.values[..rfill]);
+        right.fill -= 1;
+        let value = ::std::mem::replace(&mut self.values[sep], value);
+        let (left, right) = self.siblings_mut(sep);
+        left.values[lfill] = value;
+        left.fill += 1;
+        if let Some(ref mut rchildren) = right.children {
+            let child = unshift(&mut rchildren[..rfill + 1]);
+            left.children.as_mut().unwrap()[lfill + 1] = child;
+        }
+    }
+
+    /// Merges the `sep + 1`th child and the separator into the `sep`th child.
+    fn merge(&mut self, sep: usize) {
+        let fill = self.fill as usize;
+        let value = unshift(&mut self.values[sep..fill]);
+        let mut right = unshift(&mut self.children.as_mut().unwrap()[sep + 1..fill + 1]).unwrap();
+        self.fill -= 1;
+
+        let left = self.child_mut(sep);
+        let (lfill, rfill) = (left.fill as usize, right.fill as usize);
+        left.values[lfill] = value;
+        for (dst, src) in left.values[lfill + 1..].iter_mut().zip(right.values[..rfill].iter_mut()) {
+            *dst = take(src);
+        }
+        left.fill += 1 + right.fill;
+        if let Some(mut rchildren) = right.children {
+            let lchildren = left.children.as_mut().unwrap();
+            for (dst, src) in lchildren[lfill + 1..].iter_mut().zip(rchildren[..rfill + 1].iter_mut()) {
+                *dst = take(src);
+            }
+        }
+    }
+
+    /// Moves the keys at and after `key` into a new tree, which it returns.
+    ///
+    /// Every node on the search path is cut in two, and the pieces on
+    /// either side are joined back together from the bottom up. Each join
+    /// takes time proportional to the difference in height of its trees,
+    /// and these add up to the height of the tree.
+    pub fn split_off<Q, C>(&mut self, key: &Q, cmp: &C) -> Node<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let found = self.insertion_point(key, cmp);
+        let (mut keys, children) = replace(self, Node::empty()).into_parts();
+        let i = match found {
+            Ok(i) | Err(i) => i,
+        };
+        let mut rkeys = keys.split_off(i);
+        let mut children = match children {
+            None => {
+                *self = Node::from_parts(keys, None);
+                return Node::from_parts(rkeys, None);
+            }
+            Some(children) => children,
+        };
+        let rchildren = children.split_off(i + 1);
+        let (left, right) = if found.is_ok() {
+            let sep = rkeys.remove(0);
+            let mut right = Node::empty();
+            right.join(sep, Node::from_parts(rkeys, Some(rchildren)));
+            (Node::from_parts(keys, Some(children)), right)
+        } else {
+            let mut left = *children.pop().unwrap();
+            let mut right = left.split_off(key, cmp);
+            if let Some(sep) = keys.pop() {
+                let lower = replace(&mut left, Node::from_parts(keys, Some(children)));
+                left.join(sep, lower);
+            }
+            if !rkeys.is_empty() {
+                let sep = rkeys.remove(0);
+                right.join(sep, Node::from_parts(rkeys, Some(rchildren)));
+            }
+            (left, right)
+        };
+        *self = left;
+        right
+    }
+
+    /// Appends `key` and then the tree `right` to this tree, where all keys
+    /// are in order. Either tree may be empty, and their roots may have any
+    /// number of keys. The shorter tree is hung from the edge of the taller
+    /// one, at the level where their heights match.
+    pub fn join(&mut self, key: T, mut right: Node<T>) {
+        if self.fill == 0 {
+            swap(self, &mut right);
+            let path = self.edge(self.height(), false);
+            unsafe { Node::insert_at_path(&path, key, None) };
+            return;
+        }
+        if right.fill == 0 {
+            let path = self.edge(self.height(), true);
+            unsafe { Node::insert_at_path(&path, key, None) };
+            return;
+        }
+        let (lheight, rheight) = (self.height(), right.height());
+        match lheight.cmp(&rheight) {
+            Ordering::Equal => {
+                self.spill_root(key, Box::new(right));
+                self.refill_child(0);
+                self.refill_child(1);
+            }
+            Ordering::Greater => unsafe {
+                let path = self.edge(lheight - rheight - 1, true);
+                Node::insert_at_path(&path, key, Some(Box::new(right)));
+                // The root of `right` may be short of keys, and making up
+                // for that may leave its ancestors short in turn.
+                for &(node, i) in self.edge(self.height() - rheight - 1, true).iter().rev() {
+                    (*node).refill_child(i);
+                }
+            },
+            Ordering::Less => unsafe {
+                let left = replace(self, right);
+                let path = self.edge(rheight - lheight - 1, false);
+                let node = path.last().unwrap().0;
+                let first = (*node).children.as_mut().unwrap()[0].replace(Box::new(left));
+                Node::insert_at_path(&path, key, first);
+                for &(node, i) in self.edge(self.height() - lheight - 1, false).iter().rev() {
+                    (*node).refill_child(i);
+                }
+            },
+        }
+        self.collapse_root();
+    }
+
+    /// The path down the right edge of the tree, or the left one, through
+    /// `levels` nodes below the root.
+    fn edge(&mut self, levels: usize, right: bool) -> Vec<(*mut Node<T>, usize)> {
+        let mut path = Vec::with_capacity(levels + 1);
+        let mut node: *mut Node<T> = self;
+        for level in 0..levels + 1 {
+            let node_ref = unsafe { &mut *node };
+            let i = if right { node_ref.fill as usize } else { 0 };
+            path.push((node, i));
+            if level < levels {
+                node = node_ref.child_mut(i);
+            }
+        }
+        path
+    }
+
+    /// The node's keys and children, moved out.
+    fn into_parts(mut self) -> (Vec<T>, Option<Children<T>>) {
+        let fill = self.fill as usize;
+        let keys = self.values[..fill].iter_mut().map(take).collect();
+        let children = self.children.map(|mut children| {
+            children[..fill + 1].iter_mut().map(|child| child.take().unwrap()).collect()
+        });
+        (keys, children)
+    }
+
+    /// A node with `keys` and, unless it is a leaf, one more child. Without
+    /// keys, that child is the node.
+    fn from_parts(keys: Vec<T>, children: Option<Children<T>>) -> Node<T> {
+        if keys.is_empty() {
+            if let Some(mut children) = children {
+                return *children.pop().unwrap();
+            }
+        }
+        let mut node = Node::empty();
+        node.fill = keys.len() as u8;
+        for (value, key) in node.values.iter_mut().zip(keys) {
+            *value = key;
+        }
+        if let Some(children) = children {
+            let mut slots: [Option<Box<Node<T>>>; B + 1] = Default::default();
+            for (slot, child) in slots.iter_mut().zip(children) {
+                *slot = Some(child);
+            }
+            node.children = Some(slots);
+        }
+        node
+    }
+
+    /// The number of levels below this node.
+    pub fn height(&self) -> usize {
+        let mut height = 0;
+        let mut node = self;
+        while let Some(child) = node.child(0) {
+            height += 1;
+            node = child;
+        }
+        height
+    }
+
+    pub fn key_count(&self) -> usize {
+        let children = match self.children {
+            None => return self.fill as usize,
+            Some(ref children) => children,
+        };
+        children[..self.fill as usize + 1].iter()
+            .map(|child| child.as_ref().unwrap().key_count())
+            .sum::<usize>() + self.fill as usize
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        let mut node = self;
+        while let Some(child) = node.child(0) {
+            node = child;
+        }
+        node.values().first()
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        let mut node = self;
+        while let Some(child) = node.child(node.fill as usize) {
+            node = child;
+        }
+        node.values().last()
+    }
+
+    /// Removes the first key of the tree, which must not be empty.
+    pub fn take_first(&mut self) -> T {
+        let first = self.pop_first();
+        self.collapse_root();
+        first
+    }
+
+    /// Removes the last key of the tree, which must not be empty.
+    pub fn take_last(&mut self) -> T {
+        let last = self.pop_last();
+        self.collapse_root();
+        last
+    }
+
+    pub fn node_count(&self) -> usize {
+        let children = match self.children {
+            None => return 1,
+            Some(ref children) => children,
+        };
+        children[..self.fill as usize + 1].iter()
+            .map(|child| child.as_ref().unwrap().node_count())
+            .sum::<usize>() + 1
+    }
+
+    pub fn collect_stats(&self, level: usize, stats: &mut super::Stats) {
+        if stats.nodes_per_level.len() <= level {
+            stats.nodes_per_level.push(0);
+        }
+        stats.nodes_per_level[level] += 1;
+        stats.fill_histogram[self.fill as usize] += 1;
+        match self.children {
+            None => stats.leaves += 1,
+            Some(ref children) => {
+                stats.internal += 1;
+                for child in &children[..self.fill as usize + 1] {
+                    child.as_ref().unwrap().collect_stats(level + 1, stats);
+                }
+            }
+        }
+    }
+
+    /// Appends DOT statements for the subtree, numbering nodes from `*next_id`.
+    /// Each node is a record with a port between every two keys, which the
+    /// edge to the child in that gap starts from. Returns the node's name.
+    pub fn graph_vis(&self, next_id: &mut usize, out: &mut String) -> String
+        where T: ::std::fmt::Display
+    {
+        let name = format!("n{}", *next_id);
+        *next_id += 1;
+        let mut label = String::from("<c0>");
+        for (i, value) in self.values().iter().enumerate() {
+            label += &format!("|{}|<c{}>", ::dot_label(&value.to_string(), true), i + 1);
+        }
+        *out += &format!("{} [label=\"{}\"];\n", name, label);
+        if let Some(ref children) = self.children {
+            for (i, child) in children[..self.fill as usize + 1].iter().enumerate() {
+                let child = child.as_ref().unwrap().graph_vis(next_id, out);
+                *out += &format!("{}:c{} -> {};\n", name, i, child);
+            }
+        }
+        name
+    }
+
+    /// Panics on the first broken invariant. Keys must lie strictly between
+    /// `lower` and `upper`, the separators around this node, and all leaves
+    /// must be at the depth of the first one. Returns the number of keys.
+    pub fn check_invariants<C>(&self, lower: Option<&T>, upper: Option<&T>, depth: usize,
+                               leaf_depth: &mut Option<usize>, cmp: &C) -> usize
+        where T: ::std::fmt::Debug, C: Comparator<T>
+    {
+        assert!(self.fill as usize <= B, "Overfull node");
+        if depth > 0 {
+            assert!(self.fill as usize >= MIN_FILL, "Underfull node at depth {}", depth);
+        }
+        let values = self.values();
+        assert!(values.windows(2).all(|w| cmp.less(&w[0], &w[1])), "Keys out of order: {:?}", values);
+        if let (Some(lower), Some(first)) = (lower, values.first()) {
+            assert!(cmp.less(lower, first), "Key {:?} not above separator {:?}", first, lower);
+        }
+        if let (Some(upper), Some(last)) = (upper, values.last()) {
+            assert!(cmp.less(last, upper), "Key {:?} not below separator {:?}", last, upper);
+        }
+
+        let children = match self.children {
+            None => {
+                let leaf_depth = *leaf_depth.get_or_insert(depth);
+                assert_eq!(depth, leaf_depth, "Leaves at different depths");
+                return values.len();
+            }
+            Some(ref children) => children,
+        };
+        assert!(leaf_depth.is_none_or(|leaf_depth| depth < leaf_depth),
+                "Internal node at the depth of leaves");
+        assert!(children[values.len() + 1..].iter().all(Option::is_none),
+                "Child past the last key");
+        let mut keys = values.len();
+        for (i, child) in children[..values.len() + 1].iter().enumerate() {
+            let child = child.as_ref().expect("Missing child");
+            let lower = if i == 0 { lower } else { Some(&values[i - 1]) };
+            let upper = if i == values.len() { upper } else { Some(&values[i]) };
+            keys += child.check_invariants(lower, upper, depth + 1, leaf_depth, cmp);
+        }
+        keys
+    }
+
+    fn child(&self, i: usize) -> Option<&Node<T>> {
+        self.children.as_ref().map(|children| &**children[i].as_ref().unwrap())
+    }
+
+    fn child_mut(&mut self, i: usize) -> &mut Node<T> {
+        self.children.as_mut().unwrap()[i].as_mut().unwrap()
+    }
+
+    fn siblings_mut(&mut self, sep: usize) -> (&mut Node<T>, &mut Node<T>) {
+        let (left, right) = self.children.as_mut().unwrap().split_at_mut(sep + 1);
+        (left[sep].as_mut().unwrap(), right[0].as_mut().unwrap())
+    }
+
+    /// The `i`th child, as a pointer to keep in a path.
+    pub(super) fn child_ptr(&self, i: usize) -> Option<*mut Node<T>> {
+        self.child(i).map(|child| child as *const Node<T> as *mut Node<T>)
+    }
+
+    pub(super) fn len(&self) -> usize { self.fill as usize }
+    pub(super) fn key(&self, i: usize) -> &T { &self.values[i] }
     fn values(&self) -> &[T] { &self.values[..self.fill as usize] }
-    fn is_full(&self) -> bool { self.fill as usize == B }
+    pub(super) fn is_full(&self) -> bool { self.fill as usize == B }
 
-    fn insertion_point(&self, key: T) -> Result<usize, usize> {
+    /// The first key at or after `bound`, in the order of `cmp`.
+    fn seek<Q, C>(&self, bound: Bound<&Q>, cmp: &C) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut result = None;
+        let mut node = self;
+        loop {
+            let i = match bound {
+                Bound::Unbounded => 0,
+                Bound::Included(key) => match node.insertion_point(key, cmp) {
+                    Ok(i) => return Some(&node.values[i]),
+                    Err(i) => i,
+                },
+                Bound::Excluded(key) => match node.insertion_point(key, cmp) {
+                    Ok(i) => return match node.child(i + 1) {
+                        Some(child) => child.seek(Bound::Unbounded, cmp),
+                        None => node.values().get(i + 1).or(result),
+                    },
+                    Err(i) => i,
+                },
+            };
+            if i < node.fill as usize {
+                result = Some(&node.values[i]);
+            }
+            match node.child(i) {
+                Some(child) => node = child,
+                None => return result,
+            }
+        }
+    }
+
+    pub(super) fn insertion_point<Q, C>(&self, key: &Q, cmp: &C) -> Result<usize, usize>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
         for (index, value) in self.values().iter().enumerate() {
-            match key.cmp(value) {
+            match cmp.compare(key, value.borrow()) {
                 Ordering::Equal => return Ok(index),
-                Ordering::Greater => return Err(index),
-                Ordering::Less => {}
+                Ordering::Less => return Err(index),
+                Ordering::Greater => {}
             }
         }
-        return Err(self.values().len())
+        Err(self.values().len())
 //        self.values().binary_search(&key)
     }
 }
 
 
+/// In-order iterator over a subtree.
+///
+/// The stack holds the path to the current position: for every node the
+/// index of the next value to yield, with everything before that value
+/// already consumed or sitting deeper in the stack. The iteration stops at
+/// `end`, the first key past the range, which is found up front so that
+/// the bounds need not outlive the constructor.
+pub struct Range<'a, T: 'a> {
+    stack: Vec<(&'a Node<T>, usize)>,
+    end: Option<&'a T>,
+}
+
+
+impl<'a, T: Default> Range<'a, T> {
+    pub(super) fn new<Q, C>(root: &'a Node<T>, start: Bound<&Q>, end: Bound<&Q>, cmp: &C)
+                            -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let end = match end {
+            Bound::Unbounded => None,
+            Bound::Included(key) => root.seek(Bound::Excluded(key), cmp),
+            Bound::Excluded(key) => root.seek(Bound::Included(key), cmp),
+        };
+        let mut result = Range { stack: Vec::new(), end };
+        if let (Some(first), Some(end)) = (root.seek(start, cmp), end) {
+            if !cmp.less(first.borrow(), end.borrow()) {
+                return result;
+            }
+        }
+        let mut node = root;
+        loop {
+            let i = match start {
+                Bound::Unbounded => 0,
+                Bound::Included(key) => match node.insertion_point(key, cmp) {
+                    Ok(i) => {
+                        result.stack.push((node, i));
+                        return result;
+                    }
+                    Err(i) => i,
+                },
+                Bound::Excluded(key) => match node.insertion_point(key, cmp) {
+                    Ok(i) => {
+                        result.stack.push((node, i + 1));
+                        if let Some(child) = node.child(i + 1) {
+                            result.descend(child);
+                        }
+                        return result;
+                    }
+                    Err(i) => i,
+                },
+            };
+            result.stack.push((node, i));
+            match node.child(i) {
+                Some(child) => node = child,
+                None => return result,
+            }
+        }
+    }
+
+    fn descend(&mut self, mut node: &'a Node<T>) {
+        loop {
+            self.stack.push((node, 0));
+            match node.child(0) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+    }
+}
+
+
+impl<'a, T: Default> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            let (node, i) = match self.stack.last_mut() {
+                None => return None,
+                Some(top) => {
+                    top.1 += 1;
+                    (top.0, top.1 - 1)
+                }
+            };
+            if i >= node.fill as usize {
+                self.stack.pop();
+                continue;
+            }
+            let key = &node.values[i];
+            if self.end.is_some_and(|end| ptr::eq(key, end)) {
+                self.stack.clear();
+                return None;
+            }
+            if let Some(child) = node.child(i + 1) {
+                self.descend(child);
+            }
+            return Some(key);
+        }
+    }
+}
+
+
 #[cfg(test)]
 const XS: [u8; B] = [2, 4, 6, 8, 10, 12];
 
@@ -140,7 +999,7 @@ fn test_leaf_insert_existing() {
         values: XS,
         children: None,
     };
-    assert!(l.insert(2).is_none());
+    assert!(matches!(l.insert(2, &Natural), Insert::Found));
     assert_eq!(l.fill, 6);
     assert_eq!(l.values, XS);
 }
@@ -153,7 +1012,7 @@ fn test_leaf_insert_not_split() {
         values: [4, 6, 8, 10, 12, 0],
         children: None,
     };
-    assert!(l.insert(2).is_none());
+    assert!(matches!(l.insert(2, &Natural), Insert::Fit));
     assert_eq!(l.fill, 6);
     assert_eq!(l.values, XS);
 }
@@ -166,7 +1025,10 @@ fn test_leaf_insert_split() {
         values: XS,
         children: None,
     };
-    let (mid, r) = l.insert(3).expect("Expected a split");
+    let (mid, r) = match l.insert(3, &Natural) {
+        Insert::Split(mid, r) => (mid, r),
+        _ => panic!("Expected a split"),
+    };
     assert_eq!(l.fill, 3);
     assert_eq!(l.values, [2, 3, 4, 0, 0, 0]);
     assert_eq!(mid, 6);
@@ -0,0 +1,455 @@
+//! A persistent `BTree`. Versions share their nodes through `Arc`, and an
+//! update copies only the nodes on the path from the root to the keys it
+//! changes, so taking a snapshot is cloning the root pointer. Nodes keep
+//! their keys and children in vectors, because a copied node only needs
+//! room for what it holds.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem::{replace, size_of};
+use std::ops::{Bound, RangeBounds};
+use std::ptr;
+use std::sync::Arc;
+use super::{B, MIN_FILL};
+use super::super::IntSet;
+use compare::{Comparator, Natural};
+use key::Key;
+use memory::MemoryUsage;
+
+
+#[derive(Clone, Debug)]
+struct Node<T> {
+    keys: Vec<T>,
+    /// Empty in leaves; one more than the keys otherwise.
+    children: Vec<Arc<Node<T>>>,
+}
+
+
+/// An immutable set ordered by the comparator `C`. `insert` and `remove`
+/// return a new version and leave this one as it was; `clone` is a
+/// snapshot that takes constant time.
+pub struct PersistentBTree<T, C = Natural> {
+    root: Arc<Node<T>>,
+    len: usize,
+    cmp: C,
+}
+
+
+impl<T: Clone + Ord> PersistentBTree<T> {
+    pub fn new() -> PersistentBTree<T> { PersistentBTree::with_comparator(Natural) }
+}
+
+
+impl<T: Clone, C: Comparator<T> + Clone> PersistentBTree<T, C> {
+    pub fn with_comparator(cmp: C) -> PersistentBTree<T, C> {
+        PersistentBTree { root: Arc::new(Node::leaf()), len: 0, cmp }
+    }
+
+    pub fn comparator(&self) -> &C { &self.cmp }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// The key in the set that compares equal to `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut node = &*self.root;
+        loop {
+            match search(&node.keys, key, &self.cmp) {
+                Ok(i) => return Some(&node.keys[i]),
+                Err(i) => node = node.children.get(i)?,
+            }
+        }
+    }
+
+    /// The version with `key` added. If `key` is already in the set, that
+    /// is this version again.
+    pub fn insert(&self, key: T) -> PersistentBTree<T, C> {
+        let mut version = self.clone();
+        if self.contains(&key) {
+            return version;
+        }
+        if let Some((key, right)) = Arc::make_mut(&mut version.root).insert(key, &self.cmp) {
+            let left = replace(&mut version.root, Arc::new(Node::leaf()));
+            version.root = Arc::new(Node { keys: vec![key], children: vec![left, Arc::new(right)] });
+        }
+        version.len += 1;
+        version
+    }
+
+    /// The version without `key`.
+    pub fn remove<Q>(&self, key: &Q) -> PersistentBTree<T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut version = self.clone();
+        if !self.contains(key) {
+            return version;
+        }
+        let root = Arc::make_mut(&mut version.root);
+        root.remove(key, &self.cmp);
+        if root.keys.is_empty() {
+            if let Some(child) = root.children.pop() {
+                version.root = child;
+            }
+        }
+        version.len -= 1;
+        version
+    }
+
+    /// The keys in order.
+    pub fn iter(&self) -> Range<'_, T> { self.range::<T, _>(..) }
+
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>
+    {
+        Range::new(&self.root, range.start_bound(), range.end_bound(), &self.cmp)
+    }
+
+    /// Panics if the order, the fill or the depth of leaves is off.
+    pub fn check_invariants(&self) where T: fmt::Debug {
+        let keys = self.root.check_invariants(None, None, 0, &mut None, &self.cmp);
+        assert_eq!(keys, self.len, "Wrong length");
+    }
+
+    /// The nodes reachable from this version, each with its reference
+    /// counts and the room its vectors have for keys and children. Nodes
+    /// shared with other versions count in full.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let node_size = size_of::<Node<T>>() + 2 * size_of::<usize>();
+        let (nodes, heap) = self.root.heap_size();
+        MemoryUsage {
+            nodes,
+            node_size,
+            total: nodes * node_size + heap + size_of::<Self>(),
+            keys: self.len * size_of::<T>(),
+        }
+    }
+}
+
+
+impl<T, C: Clone> Clone for PersistentBTree<T, C> {
+    fn clone(&self) -> Self {
+        PersistentBTree { root: self.root.clone(), len: self.len, cmp: self.cmp.clone() }
+    }
+}
+
+
+impl<T: Clone, C: Comparator<T> + Clone + Default> Default for PersistentBTree<T, C> {
+    fn default() -> Self { PersistentBTree::with_comparator(C::default()) }
+}
+
+
+impl<T: fmt::Debug, C> fmt::Debug for PersistentBTree<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PersistentBTree").field("root", &self.root).field("len", &self.len).finish()
+    }
+}
+
+
+impl<T: Clone> Node<T> {
+    fn leaf() -> Node<T> { Node { keys: Vec::new(), children: Vec::new() } }
+
+    fn is_leaf(&self) -> bool { self.children.is_empty() }
+
+    /// The child at `i`, copied first if another version shares it.
+    fn child_mut(&mut self, i: usize) -> &mut Node<T> { Arc::make_mut(&mut self.children[i]) }
+
+    /// Inserts a key that is not in the tree yet, returning the separator
+    /// and the right half if the node split.
+    fn insert<C: Comparator<T>>(&mut self, key: T, cmp: &C) -> Option<(T, Node<T>)> {
+        let i = search(&self.keys, &key, cmp).unwrap_err();
+        if self.is_leaf() {
+            self.keys.insert(i, key);
+        } else {
+            let (key, right) = self.child_mut(i).insert(key, cmp)?;
+            self.keys.insert(i, key);
+            self.children.insert(i + 1, Arc::new(right));
+        }
+        if self.keys.len() <= B {
+            return None;
+        }
+        let right_keys = self.keys.split_off(B / 2 + 1);
+        let right_children = if self.is_leaf() { Vec::new() } else { self.children.split_off(B / 2 + 1) };
+        let separator = self.keys.pop().unwrap();
+        Some((separator, Node { keys: right_keys, children: right_children }))
+    }
+
+    /// Removes a key that is in the tree.
+    fn remove<Q, C>(&mut self, key: &Q, cmp: &C) -> T
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let (i, removed) = match search(&self.keys, key, cmp) {
+            Ok(i) if self.is_leaf() => return self.keys.remove(i),
+            Ok(i) => {
+                // Replace the key with its predecessor, the last key of the
+                // subtree before it.
+                let predecessor = self.child_mut(i).pop_last();
+                (i, replace(&mut self.keys[i], predecessor))
+            }
+            Err(i) => (i, self.child_mut(i).remove(key, cmp)),
+        };
+        self.fix_child(i);
+        removed
+    }
+
+    fn pop_last(&mut self) -> T {
+        if self.is_leaf() {
+            return self.keys.pop().unwrap();
+        }
+        let last = self.keys.len();
+        let result = self.child_mut(last).pop_last();
+        self.fix_child(last);
+        result
+    }
+
+    /// Refills the child at `i` from a sibling, or merges it with one, if it
+    /// has fewer than `MIN_FILL` keys.
+    fn fix_child(&mut self, i: usize) {
+        if self.children[i].keys.len() >= MIN_FILL {
+            return;
+        }
+        if i > 0 && self.children[i - 1].keys.len() > MIN_FILL {
+            let (key, child) = {
+                let left = self.child_mut(i - 1);
+                (left.keys.pop().unwrap(), left.children.pop())
+            };
+            let separator = replace(&mut self.keys[i - 1], key);
+            let node = self.child_mut(i);
+            node.keys.insert(0, separator);
+            if let Some(child) = child {
+                node.children.insert(0, child);
+            }
+        } else if i < self.keys.len() && self.children[i + 1].keys.len() > MIN_FILL {
+            let (key, child) = {
+                let right = self.child_mut(i + 1);
+                let child = if right.is_leaf() { None } else { Some(right.children.remove(0)) };
+                (right.keys.remove(0), child)
+            };
+            let separator = replace(&mut self.keys[i], key);
+            let node = self.child_mut(i);
+            node.keys.push(separator);
+            node.children.extend(child);
+        } else {
+            let j = if i > 0 { i - 1 } else { i };
+            let separator = self.keys.remove(j);
+            let right = self.children.remove(j + 1);
+            let right = Arc::try_unwrap(right).unwrap_or_else(|shared| (*shared).clone());
+            let left = self.child_mut(j);
+            left.keys.push(separator);
+            left.keys.extend(right.keys);
+            left.children.extend(right.children);
+        }
+    }
+
+    fn check_invariants<C>(&self, lower: Option<&T>, upper: Option<&T>, depth: usize,
+                           leaf_depth: &mut Option<usize>, cmp: &C) -> usize
+        where T: fmt::Debug, C: Comparator<T>
+    {
+        assert!(self.keys.len() <= B, "Overfull node");
+        if depth > 0 {
+            assert!(self.keys.len() >= MIN_FILL, "Underfull node at depth {}", depth);
+        }
+        let keys = &self.keys;
+        assert!(keys.windows(2).all(|w| cmp.less(&w[0], &w[1])), "Keys out of order: {:?}", keys);
+        if let (Some(lower), Some(first)) = (lower, keys.first()) {
+            assert!(cmp.less(lower, first), "Key {:?} not above separator {:?}", first, lower);
+        }
+        if let (Some(upper), Some(last)) = (upper, keys.last()) {
+            assert!(cmp.less(last, upper), "Key {:?} not below separator {:?}", last, upper);
+        }
+        if self.is_leaf() {
+            let leaf_depth = *leaf_depth.get_or_insert(depth);
+            assert_eq!(depth, leaf_depth, "Leaves at different depths");
+            return keys.len();
+        }
+        assert_eq!(self.children.len(), keys.len() + 1, "Wrong number of children");
+        let mut count = keys.len();
+        for (i, child) in self.children.iter().enumerate() {
+            let lower = if i == 0 { lower } else { Some(&keys[i - 1]) };
+            let upper = keys.get(i).or(upper);
+            count += child.check_invariants(lower, upper, depth + 1, leaf_depth, cmp);
+        }
+        count
+    }
+}
+
+
+impl<T> Node<T> {
+    /// The number of nodes and the bytes their vectors take on the heap.
+    fn heap_size(&self) -> (usize, usize) {
+        let own = self.keys.capacity() * size_of::<T>()
+            + self.children.capacity() * size_of::<Arc<Node<T>>>();
+        self.children.iter().map(|child| child.heap_size())
+            .fold((1, own), |(nodes, heap), (n, h)| (nodes + n, heap + h))
+    }
+}
+
+
+/// The position of `key` in `keys`, or where it would go.
+fn search<T, Q, C>(keys: &[T], key: &Q, cmp: &C) -> Result<usize, usize>
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    for (i, k) in keys.iter().enumerate() {
+        match cmp.compare(key, k.borrow()) {
+            Ordering::Equal => return Ok(i),
+            Ordering::Less => return Err(i),
+            Ordering::Greater => {}
+        }
+    }
+    Err(keys.len())
+}
+
+
+/// An iterator over a range of keys of one version.
+pub struct Range<'a, T: 'a> {
+    /// The nodes down to the next key, each with the index of the next key
+    /// to yield from it. Everything before that key has been visited.
+    stack: Vec<(&'a Node<T>, usize)>,
+    /// The first key after the range, or null.
+    end: *const T,
+}
+
+
+impl<'a, T> Range<'a, T> {
+    fn new<Q, C>(root: &'a Node<T>, start: Bound<&Q>, end: Bound<&Q>, cmp: &C) -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let end = match end {
+            Bound::Unbounded => None,
+            Bound::Included(key) => Range::seek(root, Bound::Excluded(key), cmp).peek(),
+            Bound::Excluded(key) => Range::seek(root, Bound::Included(key), cmp).peek(),
+        };
+        let mut range = Range::seek(root, start, cmp);
+        range.end = end.map_or(ptr::null(), |end| end as *const T);
+        // A range whose start is past its end is empty.
+        if let (Some(first), Some(end)) = (range.peek(), end) {
+            if !cmp.less(first.borrow(), end.borrow()) {
+                range.stack.clear();
+            }
+        }
+        range
+    }
+
+    /// The range from the first key at or after `start` to the end.
+    fn seek<Q, C>(root: &'a Node<T>, start: Bound<&Q>, cmp: &C) -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut stack = Vec::new();
+        let mut node = root;
+        loop {
+            let i = match start {
+                Bound::Unbounded => 0,
+                Bound::Included(key) | Bound::Excluded(key) => match search(&node.keys, key, cmp) {
+                    Ok(i) if matches!(start, Bound::Included(_)) => {
+                        stack.push((node, i));
+                        break;
+                    }
+                    Ok(i) => i + 1,
+                    Err(i) => i,
+                },
+            };
+            stack.push((node, i));
+            match node.children.get(i) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        let mut range = Range { stack, end: ptr::null() };
+        range.skip_finished();
+        range
+    }
+
+    fn peek(&self) -> Option<&'a T> { self.stack.last().map(|&(node, i)| &node.keys[i]) }
+
+    /// Pops the nodes whose keys have all been visited.
+    fn skip_finished(&mut self) {
+        while let Some(&(node, i)) = self.stack.last() {
+            if i < node.keys.len() {
+                return;
+            }
+            self.stack.pop();
+        }
+    }
+}
+
+
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let key = self.peek()?;
+        if ptr::eq(key, self.end) {
+            return None;
+        }
+        let top = self.stack.last_mut().unwrap();
+        top.1 += 1;
+        let (node, i) = *top;
+        let mut child = node.children.get(i);
+        while let Some(node) = child {
+            self.stack.push((node, 0));
+            child = node.children.first();
+        }
+        self.skip_finished();
+        Some(key)
+    }
+}
+
+
+impl<K: Key> IntSet<K> for PersistentBTree<K> {
+    fn empty() -> Self { PersistentBTree::new() }
+
+    fn get(&self, key: &K) -> bool { self.contains(key) }
+
+    fn put(&mut self, key: K) { *self = self.insert(key); }
+
+    fn len(&self) -> usize { self.len() }
+
+    fn remove(&mut self, key: &K) -> bool {
+        let len = self.len;
+        *self = PersistentBTree::remove(self, key);
+        self.len < len
+    }
+
+    fn scan(&self, from: &K, count: usize) -> i64 {
+        self.range(from..).take(count).fold(0, |acc, key| acc.wrapping_add(key.checksum()))
+    }
+
+    fn memory_usage(&self) -> Option<MemoryUsage> { Some(self.memory_usage()) }
+
+    fn check_invariants(&self) { PersistentBTree::check_invariants(self) }
+}
+
+
+#[test]
+fn persistent_btree_vs_btreeset() {
+    use std::collections::BTreeSet;
+    ::test_util::compare_test::<BTreeSet<i64>, PersistentBTree<i64>>();
+}
+
+
+#[test]
+fn persistent_btree_snapshots() {
+    ::test_util::persistent_snapshots_test::<PersistentBTree<i64>>();
+}
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn persistent_btree_matches_model(ops in ::test_util::arb_ops()) {
+        use std::collections::BTreeSet;
+        ::test_util::compare_ops::<BTreeSet<i64>, PersistentBTree<i64>>(&ops);
+    }
+
+    #[test]
+    fn persistent_btree_keeps_versions(ops in ::test_util::arb_ops()) {
+        ::test_util::persistent_keeps_versions::<PersistentBTree<i64>>(&ops)?;
+    }
+}
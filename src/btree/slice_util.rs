@@ -1,5 +1,5 @@
 use std::iter;
-use std::mem::{swap, replace};
+use std::mem::{swap, take};
 use super::B;
 
 
@@ -11,22 +11,34 @@ pub fn shift<T>(slice: &mut [T], value: T) {
 }
 
 
-pub fn insert_split_spill<T: Default + Copy>(
-    xs: &[T; B],
+pub fn unshift<T: Default>(slice: &mut [T]) -> T {
+    let mut tmp = T::default();
+    for x in slice.iter_mut().rev() {
+        swap(&mut tmp, x);
+    }
+    tmp
+}
+
+
+pub type Half<T> = ([T; B], u8);
+
+
+pub fn insert_split_spill<T: Default>(
+    xs: &mut [T; B],
     insertion_point: usize,
     key: T
-) -> (([T; B], u8), T, ([T; B], u8)) {
+) -> (Half<T>, T, Half<T>) {
     let mut left: [T; B] = Default::default();
     let mut mid: T = Default::default();
     let mut right: [T; B] = Default::default();
-    let left_fill = (B + 1) / 2;
+    let left_fill = B.div_ceil(2);
     let right_fill = (B + 1) - left_fill - 1;
 
-    let (lxs, rxs) = xs.split_at(insertion_point);
+    let (lxs, rxs) = xs.split_at_mut(insertion_point);
     {
-        let source = lxs.iter().cloned()
+        let source = lxs.iter_mut().map(take)
             .chain(iter::once(key))
-            .chain(rxs.iter().cloned());
+            .chain(rxs.iter_mut().map(take));
 
         let destination = left[..left_fill].iter_mut()
             .chain(iter::once(&mut mid))
@@ -37,7 +49,7 @@ pub fn insert_split_spill<T: Default + Copy>(
         }
     }
 
-    return ((left, left_fill as u8), mid, (right, right_fill as u8))
+    ((left, left_fill as u8), mid, (right, right_fill as u8))
 }
 
 
@@ -53,9 +65,9 @@ pub fn insert_split<T: Default>(
 
     let (lxs, rxs) = xs.split_at_mut(insertion_point);
     {
-        let source = lxs.iter_mut().map(|p| replace(p, Default::default()))
+        let source = lxs.iter_mut().map(take)
             .chain(iter::once(key))
-            .chain(rxs.iter_mut().map(|p| replace(p, Default::default())));
+            .chain(rxs.iter_mut().map(take));
 
         let destination = left[..left_fill].iter_mut()
             .chain(right[..right_fill].iter_mut());
@@ -65,16 +77,16 @@ pub fn insert_split<T: Default>(
         }
     }
 
-    return (left, right)
+    (left, right)
 }
 
 
 #[test]
 fn test_insert_split_spill_start() {
-    let xs = [1, 2, 3, 4, 5, 6];
+    let mut xs = [1, 2, 3, 4, 5, 6];
     let x = 0;
     let ip = 0;
-    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&xs, ip, x);
+    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&mut xs, ip, x);
 
     assert_eq!(left, [0, 1, 2, 0, 0, 0]);
     assert_eq!(left_fill, 3);
@@ -88,10 +100,10 @@ fn test_insert_split_spill_start() {
 
 #[test]
 fn test_insert_split_spill_after_first() {
-    let xs = [2, 4, 6, 8, 10, 12];
+    let mut xs = [2, 4, 6, 8, 10, 12];
     let x = 3;
     let ip = 1;
-    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&xs, ip, x);
+    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&mut xs, ip, x);
 
     assert_eq!(left, [2, 3, 4, 0, 0, 0]);
     assert_eq!(left_fill, 3);
@@ -105,10 +117,10 @@ fn test_insert_split_spill_after_first() {
 
 #[test]
 fn test_insert_split_spill_mid() {
-    let xs = [2, 4, 6, 8, 10, 12];
+    let mut xs = [2, 4, 6, 8, 10, 12];
     let x = 7;
     let ip = 3;
-    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&xs, ip, x);
+    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&mut xs, ip, x);
 
     assert_eq!(left, [2, 4, 6, 0, 0, 0]);
     assert_eq!(left_fill, 3);
@@ -122,10 +134,10 @@ fn test_insert_split_spill_mid() {
 
 #[test]
 fn test_insert_split_spill_last() {
-    let xs = [2, 4, 6, 8, 10, 12];
+    let mut xs = [2, 4, 6, 8, 10, 12];
     let x = 13;
     let ip = 6;
-    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&xs, ip, x);
+    let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&mut xs, ip, x);
 
     assert_eq!(left, [2, 4, 6, 0, 0, 0]);
     assert_eq!(left_fill, 3);
@@ -145,6 +157,14 @@ fn test_shift() {
 }
 
 
+#[test]
+fn test_unshift() {
+    let mut xs = [1, 2, 3];
+    assert_eq!(unshift(&mut xs), 1);
+    assert_eq!(xs, [2, 3, 0]);
+}
+
+
 #[test]
 fn test_insert_split_start() {
     let mut xs = [1, 2, 3, 4, 5, 6, 7];
@@ -192,3 +212,55 @@ fn test_insert_split_last() {
     assert_eq!(left, [2, 4, 6, 8, 0, 0, 0]);
     assert_eq!(right, [10, 12, 14, 15, 0, 0, 0]);
 }
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn prop_shift(xs in ::proptest::collection::vec(1..100i32, 1..10), x in 1..100i32) {
+        let mut shifted = xs.clone();
+        shift(&mut shifted, x);
+        let mut expected = xs.clone();
+        expected.insert(0, x);
+        expected.pop();
+        assert_eq!(shifted, expected);
+
+        assert_eq!(unshift(&mut shifted), x);
+        let mut expected = xs.clone();
+        *expected.last_mut().unwrap() = 0;
+        assert_eq!(shifted, expected);
+    }
+
+    #[test]
+    fn prop_insert_split_spill(xs: [i32; B], ip in 0..B + 1, key in 1..100i32) {
+        let mut values = xs;
+        let ((left, left_fill), mid, (right, right_fill)) = insert_split_spill(&mut values, ip, key);
+        let (left_fill, right_fill) = (left_fill as usize, right_fill as usize);
+        assert_eq!(left_fill + right_fill, B);
+        assert!(left_fill - right_fill <= 1);
+        assert!(left[left_fill..].iter().chain(&right[right_fill..]).all(|&x| x == 0));
+
+        let mut expected = xs.to_vec();
+        expected.insert(ip, key);
+        let actual: Vec<i32> = left[..left_fill].iter()
+            .chain(Some(&mid))
+            .chain(&right[..right_fill])
+            .cloned()
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn prop_insert_split(xs: [i32; B + 1], ip in 0..B + 2, key: i32) {
+        let mut children = xs;
+        let (left, right) = insert_split(&mut children, ip, key);
+        let left_fill = (B + 2) / 2;
+        let right_fill = B + 2 - left_fill;
+        assert!(left[left_fill..].iter().chain(&right[right_fill..]).all(|&x| x == 0));
+
+        let mut expected = xs.to_vec();
+        expected.insert(ip, key);
+        let actual: Vec<i32> = left[..left_fill].iter().chain(&right[..right_fill]).cloned().collect();
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,106 @@
+//! Orderings for the trees.
+//!
+//! Both trees take a `Comparator` that decides the order of their keys, so
+//! that keys can be ordered at runtime, for example by a collation or in
+//! reverse. The default, `Natural`, is the key's own `Ord`; it is a
+//! zero-sized type whose comparison inlines to `Ord::cmp`, so trees using it
+//! are as fast and as small as before.
+
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+
+
+pub trait Comparator<T: ?Sized> {
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+
+    fn less(&self, a: &T, b: &T) -> bool { self.compare(a, b) == Ordering::Less }
+}
+
+
+/// The order given by `Ord`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Natural;
+
+
+impl<T: Ord + ?Sized> Comparator<T> for Natural {
+    #[inline(always)]
+    fn compare(&self, a: &T, b: &T) -> Ordering { a.cmp(b) }
+}
+
+
+/// The opposite of the order of `C`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reverse<C = Natural>(pub C);
+
+
+impl<T: ?Sized, C: Comparator<T>> Comparator<T> for Reverse<C> {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering { self.0.compare(b, a) }
+}
+
+
+/// Any function comparing two keys, such as a closure capturing a collation
+/// table.
+impl<T: ?Sized, F: Fn(&T, &T) -> Ordering> Comparator<T> for F {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering { self(a, b) }
+}
+
+
+/// An entry of a map, which the trees store as their keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyValue<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+
+/// A borrowed form of the keys of a map, such as `str` for `String` keys,
+/// which map entries lend out so that the trees can look them up by it.
+#[repr(transparent)]
+pub struct Query<Q: ?Sized>(Q);
+
+
+impl<Q: ?Sized> Query<Q> {
+    pub fn new(key: &Q) -> &Query<Q> {
+        // Sound, as `Query` is a transparent wrapper.
+        unsafe { &*(key as *const Q as *const Query<Q>) }
+    }
+}
+
+
+impl<K: Borrow<Q>, V, Q: ?Sized> Borrow<Query<Q>> for KeyValue<K, V> {
+    fn borrow(&self) -> &Query<Q> { Query::new(self.key.borrow()) }
+}
+
+
+/// Orders map entries by their keys alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByKey<C>(pub C);
+
+
+impl<K, V, C: Comparator<K>> Comparator<KeyValue<K, V>> for ByKey<C> {
+    #[inline]
+    fn compare(&self, a: &KeyValue<K, V>, b: &KeyValue<K, V>) -> Ordering {
+        self.0.compare(&a.key, &b.key)
+    }
+}
+
+
+impl<Q: ?Sized, C: Comparator<Q>> Comparator<Query<Q>> for ByKey<C> {
+    #[inline]
+    fn compare(&self, a: &Query<Q>, b: &Query<Q>) -> Ordering { self.0.compare(&a.0, &b.0) }
+}
+
+
+#[test]
+fn comparators() {
+    assert_eq!(Natural.compare(&1, &2), Ordering::Less);
+    assert_eq!(Reverse(Natural).compare(&1, &2), Ordering::Greater);
+    assert_eq!(Reverse(Reverse(Natural)).compare(&1, &2), Ordering::Less);
+    let by_len = |a: &&str, b: &&str| a.len().cmp(&b.len());
+    assert_eq!(by_len.compare(&"ab", &"c"), Ordering::Greater);
+    assert!(Reverse(by_len).less(&"ab", &"c"));
+    assert_eq!(::std::mem::size_of::<Natural>(), 0);
+}
@@ -0,0 +1,180 @@
+//! Sets that threads can share.
+//!
+//! `ConcurrentSet` publishes versions of a persistent set through an
+//! `ArcSwap`, as in read-copy-update: readers load the current version
+//! without taking a lock, and writers build the next version from it and
+//! swap it in. A version is freed when the last reader holding it lets go.
+
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use arc_swap::ArcSwap;
+use key::Key;
+use super::IntSet;
+
+
+/// The set interface of the multi-threaded benchmarks.
+pub trait SharedSet<K: Key>: Sync {
+    fn contains(&self, key: &K) -> bool;
+    /// Whether the key was not in the set yet.
+    fn insert(&self, key: K) -> bool;
+    fn remove(&self, key: &K) -> bool;
+    /// As `IntSet::scan`.
+    fn scan(&self, from: &K, count: usize) -> i64;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+
+/// A set with lock-free reads and one writer at a time. `A` should be a
+/// persistent set such as `PersistentBTree`, whose `clone` is a snapshot;
+/// any other set is copied in full on every write.
+pub struct ConcurrentSet<A> {
+    current: ArcSwap<A>,
+    /// Taken by writers, so that no update is lost to a concurrent one.
+    writer: Mutex<()>,
+    /// The length of `current`, which writers set while they hold `writer`.
+    len: AtomicUsize,
+}
+
+
+impl<A: Clone> ConcurrentSet<A> {
+    pub fn new<K: Key>(set: A) -> ConcurrentSet<A> where A: IntSet<K> {
+        let len = AtomicUsize::new(set.len());
+        ConcurrentSet { current: ArcSwap::from_pointee(set), writer: Mutex::new(()), len }
+    }
+
+    /// The current version, which stays as it is while writers go on.
+    pub fn snapshot(&self) -> Arc<A> { self.current.load_full() }
+
+    pub fn contains<K: Key>(&self, key: &K) -> bool where A: IntSet<K> {
+        self.current.load().get(key)
+    }
+
+    /// The length of the current version. A reader racing a writer may
+    /// get that of the version before.
+    pub fn len(&self) -> usize { self.len.load(Ordering::Acquire) }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Publishes the version `update` makes from the current one, if it
+    /// returns the new length.
+    fn write<F: FnOnce(&mut A) -> Option<usize>>(&self, update: F) -> bool {
+        let _writer = self.writer.lock().unwrap();
+        let mut next = A::clone(&self.current.load());
+        match update(&mut next) {
+            Some(len) => {
+                self.current.store(Arc::new(next));
+                self.len.store(len, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert<K: Key>(&self, key: K) -> bool where A: IntSet<K> {
+        self.write(|set| {
+            let len = set.len();
+            set.put(key);
+            Some(set.len()).filter(|&n| n > len)
+        })
+    }
+
+    pub fn remove<K: Key>(&self, key: &K) -> bool where A: IntSet<K> {
+        self.write(|set| if set.remove(key) { Some(set.len()) } else { None })
+    }
+}
+
+
+impl<K: Key, A: IntSet<K> + Clone + Send + Sync> SharedSet<K> for ConcurrentSet<A> {
+    fn contains(&self, key: &K) -> bool { ConcurrentSet::contains(self, key) }
+    fn insert(&self, key: K) -> bool { ConcurrentSet::insert(self, key) }
+    fn remove(&self, key: &K) -> bool { ConcurrentSet::remove(self, key) }
+    fn scan(&self, from: &K, count: usize) -> i64 { self.current.load().scan(from, count) }
+    fn len(&self) -> usize { ConcurrentSet::len(self) }
+}
+
+
+/// A single-threaded set behind a lock, as the baseline.
+impl<K: Key, A: IntSet<K> + Send + Sync> SharedSet<K> for RwLock<A> {
+    fn contains(&self, key: &K) -> bool { self.read().unwrap().get(key) }
+
+    fn insert(&self, key: K) -> bool {
+        let mut set = self.write().unwrap();
+        let len = set.len();
+        set.put(key);
+        set.len() > len
+    }
+
+    fn remove(&self, key: &K) -> bool { self.write().unwrap().remove(key) }
+    fn scan(&self, from: &K, count: usize) -> i64 { self.read().unwrap().scan(from, count) }
+    fn len(&self) -> usize { self.read().unwrap().len() }
+}
+
+
+/// The plainest lock, which lets in one thread at a time, readers too.
+impl<K: Key, A: IntSet<K> + Send> SharedSet<K> for Mutex<A> {
+    fn contains(&self, key: &K) -> bool { self.lock().unwrap().get(key) }
+
+    fn insert(&self, key: K) -> bool {
+        let mut set = self.lock().unwrap();
+        let len = set.len();
+        set.put(key);
+        set.len() > len
+    }
+
+    fn remove(&self, key: &K) -> bool { self.lock().unwrap().remove(key) }
+    fn scan(&self, from: &K, count: usize) -> i64 { self.lock().unwrap().scan(from, count) }
+    fn len(&self) -> usize { self.lock().unwrap().len() }
+}
+
+
+impl<K: Key, A: IntSet<K> + Clone> IntSet<K> for ConcurrentSet<A> {
+    fn empty() -> Self { ConcurrentSet::new(A::empty()) }
+    fn get(&self, key: &K) -> bool { self.contains(key) }
+    fn put(&mut self, key: K) { self.insert(key); }
+    fn len(&self) -> usize { ConcurrentSet::len(self) }
+    fn remove(&mut self, key: &K) -> bool { ConcurrentSet::remove(self, key) }
+    fn scan(&self, from: &K, count: usize) -> i64 { self.current.load().scan(from, count) }
+    fn check_invariants(&self) { self.current.load().check_invariants() }
+}
+
+
+#[test]
+fn concurrent_set_vs_btreeset() {
+    use std::collections::BTreeSet;
+    use btree::PersistentBTree;
+    ::test_util::compare_test::<BTreeSet<i64>, ConcurrentSet<PersistentBTree<i64>>>();
+}
+
+
+#[test]
+fn concurrent_readers_see_whole_versions() {
+    use std::thread;
+    use rbtree::PersistentRBTree;
+    // The writer inserts keys in order, so every version a reader sees
+    // holds exactly the keys below its length.
+    const N: i64 = 2_000;
+    let set = ConcurrentSet::new(PersistentRBTree::new());
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let mut last = 0;
+                while last < N as usize {
+                    let snapshot = set.snapshot();
+                    let len = snapshot.len();
+                    assert!(len >= last, "Versions went back from {} to {}", last, len);
+                    assert!(len == 0 || snapshot.contains(&(len as i64 - 1)));
+                    assert!(!snapshot.contains(&(len as i64)));
+                    last = len;
+                }
+            });
+        }
+        for i in 0..N {
+            assert!(set.insert(i));
+        }
+    });
+    assert!(!set.insert(0));
+    assert!(set.remove(&0));
+    assert_eq!(set.len(), N as usize - 1);
+    set.snapshot().check_invariants();
+}
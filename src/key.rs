@@ -0,0 +1,105 @@
+//! Key types for the benchmarks.
+//!
+//! Workloads are generated as integers and then mapped to the key type
+//! under test, so every structure sees the same sequence of keys whatever
+//! their type. The mappings are chosen to make comparisons realistic:
+//! pairs share the first component, byte strings share a prefix.
+
+
+use std::fmt;
+
+
+/// Keys are `Send` and `Sync`, so that the multi-threaded benchmarks can share
+/// sets of them.
+pub trait Key: Ord + Clone + Default + fmt::Debug + Send + Sync {
+    const NAME: &'static str;
+    /// The key for the integer `i`. Distinct integers give distinct keys,
+    /// except for `u32`, which cannot keep apart all of the 63-bit keys of
+    /// `ycsb` and `replay`.
+    fn from_i64(i: i64) -> Self;
+    /// A summary of the key, for checksums of scans.
+    fn checksum(&self) -> i64;
+}
+
+
+impl Key for i64 {
+    const NAME: &'static str = "i64";
+    fn from_i64(i: i64) -> Self { i }
+    fn checksum(&self) -> i64 { *self }
+}
+
+
+impl Key for u32 {
+    const NAME: &'static str = "u32";
+    /// Folds the high half in, scrambled so that small negative integers
+    /// do not land on small positive ones; below 2^32, `i` is kept.
+    fn from_i64(i: i64) -> Self {
+        let i = i as u64;
+        (i ^ (i >> 32).wrapping_mul(0x9e37_79b9)) as u32
+    }
+    fn checksum(&self) -> i64 { *self as i64 }
+}
+
+
+impl Key for u128 {
+    const NAME: &'static str = "u128";
+    fn from_i64(i: i64) -> Self { (i as u128) << 64 | i as u64 as u128 }
+    fn checksum(&self) -> i64 { *self as i64 }
+}
+
+
+impl Key for (u64, u64) {
+    const NAME: &'static str = "(u64, u64)";
+    fn from_i64(i: i64) -> Self { (i as u64 >> 4, i as u64 & 15) }
+    fn checksum(&self) -> i64 { (self.0 << 4 | self.1) as i64 }
+}
+
+
+impl Key for [u8; 16] {
+    const NAME: &'static str = "[u8; 16]";
+    fn from_i64(i: i64) -> Self {
+        let mut result = [0; 16];
+        result[8..].copy_from_slice(&((i as u64) ^ (1 << 63)).to_be_bytes());
+        result
+    }
+    fn checksum(&self) -> i64 {
+        let mut low = [0; 8];
+        low.copy_from_slice(&self[8..]);
+        (u64::from_be_bytes(low) ^ (1 << 63)) as i64
+    }
+}
+
+
+impl Key for String {
+    const NAME: &'static str = "String";
+    fn from_i64(i: i64) -> Self { format!("key-{:016x}", (i as u64) ^ (1 << 63)) }
+    fn checksum(&self) -> i64 {
+        let hex = self.trim_start_matches("key-");
+        (u64::from_str_radix(hex, 16).unwrap_or(0) ^ (1 << 63)) as i64
+    }
+}
+
+
+#[cfg(test)]
+fn check_key<K: Key>() {
+    let ints = [-1_000_000, -1, 0, 1, 15, 16, 92, 1_000_000];
+    let keys: Vec<K> = ints.iter().map(|&i| K::from_i64(i)).collect();
+    for (i, key) in ints.iter().zip(&keys) {
+        assert_eq!(K::from_i64(key.checksum()), *key, "{} {:?}", i, key);
+    }
+    let mut distinct = keys.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), keys.len(), "{}", K::NAME);
+}
+
+
+#[test]
+fn keys_are_distinct_and_checksums_roundtrip() {
+    check_key::<i64>();
+    check_key::<u32>();
+    check_key::<u128>();
+    check_key::<(u64, u64)>();
+    check_key::<[u8; 16]>();
+    check_key::<String>();
+}
@@ -0,0 +1,125 @@
+extern crate arc_swap;
+extern crate rand;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+
+
+use std::collections::BTreeSet;
+use key::Key;
+use memory::MemoryUsage;
+
+
+pub mod btree;
+pub mod compare;
+pub mod concurrent;
+pub mod key;
+pub mod memory;
+pub mod parallel;
+pub mod perf;
+pub mod profile;
+pub mod rbtree;
+pub mod rng;
+pub mod set_ops;
+#[cfg(test)]
+mod test_util;
+pub mod trace;
+pub mod workload;
+
+
+/// The set interface the benchmarks drive. Keys are `i64` unless a
+/// benchmark asks for another key type.
+pub trait IntSet<K: Key = i64> {
+    fn empty() -> Self;
+    fn get(&self, key: &K) -> bool;
+    fn put(&mut self, key: K);
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool { self.len() == 0 }
+    fn remove(&mut self, key: &K) -> bool;
+    /// Visits up to `count` keys starting from `from` in ascending order,
+    /// returning the wrapping sum of their checksums.
+    fn scan(&self, from: &K, count: usize) -> i64;
+    fn memory_usage(&self) -> Option<MemoryUsage> { None }
+    fn report(&self) {}
+    /// Panics if the internal structure is broken.
+    fn check_invariants(&self) {}
+}
+
+
+impl<K: Key> IntSet<K> for BTreeSet<K> {
+    fn empty() -> Self { BTreeSet::new() }
+
+    fn get(&self, key: &K) -> bool { self.contains(key) }
+
+    fn put(&mut self, key: K) { self.insert(key); }
+
+    fn len(&self) -> usize { self.len() }
+
+    fn remove(&mut self, key: &K) -> bool { BTreeSet::remove(self, key) }
+
+    fn scan(&self, from: &K, count: usize) -> i64 {
+        self.range(from..).take(count).fold(0, |acc, key| acc.wrapping_add(key.checksum()))
+    }
+}
+
+
+/// The nearest-key queries of the trees, which `BTreeSet` answers with
+/// ranges.
+pub trait NearestKeys<K: Key = i64>: IntSet<K> {
+    fn first(&self) -> Option<&K>;
+    fn last(&self) -> Option<&K>;
+    /// The greatest key at most `key`.
+    fn floor(&self, key: &K) -> Option<&K>;
+    /// The least key at least `key`.
+    fn ceiling(&self, key: &K) -> Option<&K>;
+    /// The greatest key less than `key`.
+    fn predecessor(&self, key: &K) -> Option<&K>;
+    /// The least key greater than `key`.
+    fn successor(&self, key: &K) -> Option<&K>;
+    fn pop_first(&mut self) -> Option<K>;
+    fn pop_last(&mut self) -> Option<K>;
+}
+
+
+impl<K: Key> NearestKeys<K> for BTreeSet<K> {
+    fn first(&self) -> Option<&K> { BTreeSet::first(self) }
+
+    fn last(&self) -> Option<&K> { BTreeSet::last(self) }
+
+    fn floor(&self, key: &K) -> Option<&K> { self.range(..=key).next_back() }
+
+    fn ceiling(&self, key: &K) -> Option<&K> { self.range(key..).next() }
+
+    fn predecessor(&self, key: &K) -> Option<&K> { self.range(..key).next_back() }
+
+    fn successor(&self, key: &K) -> Option<&K> {
+        use std::ops::Bound::{Excluded, Unbounded};
+        self.range((Excluded(key), Unbounded)).next()
+    }
+
+    fn pop_first(&mut self) -> Option<K> { BTreeSet::pop_first(self) }
+
+    fn pop_last(&mut self) -> Option<K> { BTreeSet::pop_last(self) }
+}
+
+
+/// Escapes `label` for a quoted Graphviz label. In `record` labels, the
+/// characters that lay out the fields are escaped as well.
+fn dot_label(label: &str, record: bool) -> String {
+    let mut result = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '"' | '\\' => result.push('\\'),
+            '|' | '{' | '}' | '<' | '>' | ' ' if record => result.push('\\'),
+            '\n' => {
+                result += "\\n";
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+    }
+    result
+}
@@ -1,82 +1,539 @@
 extern crate rand;
-extern crate time;
+extern crate tree_bench;
 
 
+use std::env;
+use std::process;
+use std::thread;
 use std::time::Instant;
 use std::collections::BTreeSet;
-use btree::BTree;
-use rbtree::RBTree;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use rand::Rng;
+use tree_bench::{IntSet, NearestKeys};
+use tree_bench::btree::{BTree, PersistentBTree};
+use tree_bench::key::Key;
+use tree_bench::rbtree::{PersistentRBTree, RBTree};
+use tree_bench::workload::{Op, Workload};
+use tree_bench::concurrent::{ConcurrentSet, SharedSet};
+use tree_bench::{memory, parallel, perf, rng, trace, workload};
+
+
+/// The key types accepted by `--key`.
+const KEY_TYPES: &[&str] = &["i64", "u32", "u128", "u64-pair", "bytes16", "string", "all"];
+
+
+/// Calls the generic function `$f` with the key type named by `$key`, or
+/// with each key type in turn for `all`.
+macro_rules! with_key_type {
+    ($key:expr, $f:ident($($arg:expr),*)) => {
+        match $key {
+            "i64" => $f::<i64>($($arg),*),
+            "u32" => $f::<u32>($($arg),*),
+            "u128" => $f::<u128>($($arg),*),
+            "u64-pair" => $f::<(u64, u64)>($($arg),*),
+            "bytes16" => $f::<[u8; 16]>($($arg),*),
+            "string" => $f::<String>($($arg),*),
+            "all" => {
+                $f::<i64>($($arg),*);
+                $f::<u32>($($arg),*);
+                $f::<u128>($($arg),*);
+                $f::<(u64, u64)>($($arg),*);
+                $f::<[u8; 16]>($($arg),*);
+                $f::<String>($($arg),*);
+            }
+            key => unreachable!("unknown key type: {}", key),
+        }
+    };
+}
 
 
-mod btree;
-mod rbtree;
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let rng = || {
+        let seed = rng::seed();
+        println!("seed = {} (set {} to reproduce)\n", seed, rng::SEED_VAR);
+        rng::from_seed(seed)
+    };
+    let mut perf = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--perf") {
+        args.remove(i);
+        match perf::Counters::open() {
+            Ok(counters) => {
+                for &(name, ref e) in counters.dropped() {
+                    println!("Counter {} is not available: {}", name, e);
+                }
+                perf = Some(counters);
+            }
+            Err(e) => println!("Hardware counters are not available: {}\n", e),
+        }
+    }
+    let perf = &mut perf;
+    let mut key = String::from("i64");
+    if let Some(i) = args.iter().position(|arg| arg == "--key") {
+        args.remove(i);
+        if i == args.len() {
+            exit_with(&format!("--key needs a key type\n{}", USAGE));
+        }
+        key = args.remove(i);
+        if !KEY_TYPES.contains(&key.as_str()) {
+            exit_with(&format!("unknown key type: {}\n{}", key, USAGE));
+        }
+    }
+    let key = key.as_str();
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("bench") => {
+            let mut rng = rng();
+            with_key_type!(key, insert_lookup(&mut rng, perf))
+        }
+        Some("ycsb") => {
+            let mut rng = rng();
+            with_key_type!(key, ycsb(&mut rng, perf, &args[1..]))
+        }
+        Some("setops") => {
+            let mut rng = rng();
+            with_key_type!(key, set_ops(&mut rng))
+        }
+        Some("persistent") => {
+            let mut rng = rng();
+            with_key_type!(key, persistence(&mut rng))
+        }
+        Some("scaling") if args.len() <= 2 => {
+            let max_threads = match args.get(1) {
+                Some(arg) => arg.parse().ok().filter(|&n| n > 0)
+                    .unwrap_or_else(|| exit_with(&format!("invalid thread count: {}", arg))),
+                // One core is left to the thread that updates the set.
+                None => (cores() - 1).max(1),
+            };
+            let mut rng = rng();
+            with_key_type!(key, scaling(&mut rng, max_threads))
+        }
+        Some("parallel") if args.len() >= 2 => {
+            let threads = args[1].parse().ok().filter(|&n| n > 0)
+                .unwrap_or_else(|| exit_with(&format!("invalid thread count: {}", args[1])));
+            let mut rng = rng();
+            with_key_type!(key, parallel_ycsb(&mut rng, threads, &args[2..]))
+        }
+        Some("record") if args.len() == 3 => record(&mut rng(), &args[1], &args[2]),
+        Some("replay") if args.len() == 2 => with_key_type!(key, replay(perf, &args[1])),
+        Some("dot") if args.len() >= 2 => dot(&args[1], &args[2..]),
+        Some(command @ ("scaling" | "parallel" | "record" | "replay" | "dot")) => {
+            exit_with(&format!("wrong number of arguments for {}\n{}", command, USAGE))
+        }
+        Some(command) => {
+            eprintln!("unknown command: {}\n{}", command, USAGE);
+            process::exit(1);
+        }
+    }
+}
 
 
-static mut TMP: u64 = 0;
+const USAGE: &str = "\
+usage: tree_bench [--perf] [--key <type>] [bench]
+       tree_bench [--perf] [--key <type>] ycsb [A-F | get=N,put=N,...]...
+       tree_bench [--key <type>] setops
+       tree_bench [--key <type>] persistent
+       tree_bench [--key <type>] scaling [<max-threads>]
+       tree_bench [--key <type>] parallel <threads> [A-F | get=N,put=N,...]...
+       tree_bench record <workload> <trace-file>
+       tree_bench [--perf] [--key <type>] replay <trace-file>
+       tree_bench dot (btree | rbtree) <key>...
+
+--perf reports hardware performance counters for each phase.
+--key runs the benchmarks with keys of type i64 (the default), u32, u128,
+u64-pair, bytes16, string, or with each of them for all.
+setops times union, intersection, difference and symmetric difference of
+sets of equal size and of a large set with a small one.
+persistent compares the persistent trees, which copy the path to every key
+they insert, with the mutable ones, with and without old versions kept.
+scaling runs lookups on 1, 2, 4... up to max-threads threads (by default,
+one per core but one) while another thread keeps updating the set,
+comparing the lock-free reads of the persistent trees with trees behind a
+lock.
+parallel splits the operations of each workload between the threads, which
+run them against one set behind a lock or a concurrent set, or route each
+operation to the thread owning a shard with its key, except in workloads
+with scans, which would see only one shard.
+dot prints the tree built by inserting the keys in order, in Graphviz format.
+Randomness is seeded from TREE_BENCH_SEED if it is set.";
+
+
+fn insert_lookup<K: Key>(rng: &mut impl Rng, perf: &mut Option<perf::Counters>) {
+    const N: usize = 1_000_000;
+    const M: i64 = 100_000_000;
+    let to_insert: Vec<K> = random_vec(rng, N, M).into_iter().map(K::from_i64).collect();
+    let to_lookup: Vec<K> = random_vec(rng, 100_000, M).into_iter().map(K::from_i64).collect();
+    println!("Keys: {}\n", K::NAME);
+    benchmark::<K, BTreeSet<K>>("std::collections::BTreeSet", perf, &to_insert, &to_lookup);
+    benchmark::<K, BTree<K>>("BTree", perf, &to_insert, &to_lookup);
+    benchmark::<K, RBTree<K>>("RBTree", perf, &to_insert, &to_lookup);
+}
 
 
-pub fn tick() {
-    unsafe { TMP = time::precise_time_ns() }
+fn ycsb<K: Key>(rng: &mut impl Rng, perf: &mut Option<perf::Counters>, args: &[String]) {
+    const RECORDS: usize = 1_000_000;
+    const OPERATIONS: usize = 1_000_000;
+    let workloads = if args.is_empty() {
+        Workload::presets().to_vec()
+    } else {
+        args.iter().map(|arg| Workload::parse(arg).unwrap_or_else(|e| exit_with(&e))).collect()
+    };
+
+    for workload in &workloads {
+        println!("Workload {}: {} records, {} operations, {} keys\n",
+                 workload, RECORDS, OPERATIONS, K::NAME);
+        let (load, ops) = workload.generate(rng, RECORDS, OPERATIONS);
+        warn_of_collisions::<K>(load.iter().chain(ops.iter().map(Op::key)));
+        let load: Vec<K> = load.into_iter().map(K::from_i64).collect();
+        let ops: Vec<Op<K>> = ops.into_iter().map(Op::with_key).collect();
+        run_workload::<K, BTreeSet<K>>("std::collections::BTreeSet", perf, &load, &ops);
+        run_workload::<K, BTree<K>>("BTree", perf, &load, &ops);
+        run_workload::<K, RBTree<K>>("RBTree", perf, &load, &ops);
+    }
 }
 
 
-pub fn tack(timer: &mut u64) {
-    unsafe { *timer += time::precise_time_ns() - TMP }
+/// Says so if some of the integer keys map to the same `K`, as they may
+/// for `u32`, which makes the results differ from those of other key types.
+fn warn_of_collisions<'a, K: Key>(keys: impl Iterator<Item = &'a i64>) {
+    let ints: BTreeSet<i64> = keys.cloned().collect();
+    let distinct: BTreeSet<K> = ints.iter().map(|&i| K::from_i64(i)).collect();
+    if distinct.len() < ints.len() {
+        println!("Note: the {} distinct keys are only {} distinct {} keys\n",
+                 ints.len(), distinct.len(), K::NAME);
+    }
 }
 
 
-trait IntSet {
-    fn empty() -> Self;
-    fn get(&self, key: i64) -> bool;
-    fn put(&mut self, key: i64);
-    fn report() {}
+fn set_ops<K: Key>(rng: &mut impl Rng) {
+    const M: i64 = 100_000_000;
+    let large: Vec<K> = random_vec(rng, 1_000_000, M).into_iter().map(K::from_i64).collect();
+    let other: Vec<K> = random_vec(rng, 1_000_000, M).into_iter().map(K::from_i64).collect();
+    let small: Vec<K> = random_vec(rng, 1_000, M).into_iter().map(K::from_i64).collect();
+    for &(title, a, b) in &[("Balanced", &large, &other), ("Unbalanced", &large, &small)] {
+        println!("{}: {} and {} random {} keys\n", title, a.len(), b.len(), K::NAME);
+        set_op_benchmark::<K, BTreeSet<K>>("std::collections::BTreeSet", a, b);
+        set_op_benchmark::<K, BTree<K>>("BTree", a, b);
+        set_op_benchmark::<K, RBTree<K>>("RBTree", a, b);
+    }
 }
 
 
-impl IntSet for BTreeSet<i64> {
-    fn empty() -> Self { BTreeSet::new() }
+fn persistence<K: Key>(rng: &mut impl Rng) {
+    const N: usize = 1_000_000;
+    const M: i64 = 100_000_000;
+    let to_insert: Vec<K> = random_vec(rng, N, M).into_iter().map(K::from_i64).collect();
+    let to_lookup: Vec<K> = random_vec(rng, 100_000, M).into_iter().map(K::from_i64).collect();
+    println!("Keys: {}\n", K::NAME);
+    mutable_benchmark::<K, BTree<K>>("BTree", &to_insert, &to_lookup);
+    mutable_benchmark::<K, RBTree<K>>("RBTree", &to_insert, &to_lookup);
+    versioned_benchmark::<K, PersistentBTree<K>>("PersistentBTree", &to_insert, &to_lookup);
+    versioned_benchmark::<K, PersistentRBTree<K>>("PersistentRBTree", &to_insert, &to_lookup);
+}
 
-    fn get(&self, key: i64) -> bool { self.contains(&key) }
 
-    fn put(&mut self, key: i64) { self.insert(key); }
+/// The baseline for `versioned_benchmark`.
+fn mutable_benchmark<K: Key, A: IntSet<K>>(name: &str, to_insert: &[K], to_lookup: &[K]) {
+    let mut tree = A::empty();
+    timeit(&format!("Inserting {} random keys into {}", to_insert.len(), name), || {
+        for key in to_insert {
+            tree.put(key.clone());
+        }
+    });
+    if let Some(usage) = tree.memory_usage() {
+        println!("Nodes:  {}\n", usage);
+    }
+    let hash = timeit(&format!("Retrieving {} random keys", to_lookup.len()), || {
+        to_lookup.iter().filter(|key| tree.get(key)).count()
+    });
+    println!("hash = {}\n\n", hash);
 }
 
 
-#[cfg(test)]
-fn compare_test<A, B>() where A: IntSet, B: IntSet {
-    let mut atree = A::empty();
-    let mut btree = B::empty();
+/// Times a persistent set, whose `put` and `remove` replace it with a new
+/// version and whose `clone` is a snapshot.
+fn versioned_benchmark<K: Key, A: IntSet<K> + Clone>(name: &str, to_insert: &[K], to_lookup: &[K]) {
+    const SNAPSHOT_EVERY: usize = 1_000;
+    let mut tree = A::empty();
+    let (_, allocs) = memory::measure(|| {
+        timeit(&format!("Inserting {} random keys into {}, one version each", to_insert.len(), name),
+               || {
+            for key in to_insert {
+                tree.put(key.clone());
+            }
+        })
+    });
+    if let Some(allocs) = allocs {
+        println!("Memory: {}", allocs);
+    }
+    if let Some(usage) = tree.memory_usage() {
+        println!("Nodes:  {}\n", usage);
+    }
+
+    let mut snapshots = Vec::with_capacity(to_insert.len() / SNAPSHOT_EVERY + 1);
+    let mut versions = A::empty();
+    let (_, allocs) = memory::measure(|| {
+        timeit(&format!("Inserting them again, keeping every {}th version", SNAPSHOT_EVERY), || {
+            for (i, key) in to_insert.iter().enumerate() {
+                versions.put(key.clone());
+                if i % SNAPSHOT_EVERY == 0 {
+                    snapshots.push(versions.clone());
+                }
+            }
+        })
+    });
+    if let Some(allocs) = allocs {
+        println!("Memory: {}", allocs);
+    }
+    println!("{} snapshots\n", snapshots.len());
+    drop(snapshots);
+    drop(versions);
 
-    const N: usize = 100_000;
+    let hash = timeit(&format!("Retrieving {} random keys", to_lookup.len()), || {
+        to_lookup.iter().filter(|key| tree.get(key)).count()
+    });
+    println!("hash = {}\n", hash);
 
-    for _ in 0..N {
-        let q = rand::random::<usize>();
-        let r = rand::random::<i64>() % 10_000;
-        if q % 3 == 0 {
-            atree.put(r);
-            btree.put(r);
-        } else {
-            assert_eq!(atree.get(r), btree.get(r));
+    let mut snapshots = Vec::with_capacity(to_insert.len());
+    timeit(&format!("Taking {} snapshots", to_insert.len()), || {
+        for _ in 0..to_insert.len() {
+            snapshots.push(tree.clone());
         }
-    }
+    });
+    drop(snapshots);
+    timeit(&format!("Removing {} keys, one version each", to_insert.len()), || {
+        for key in to_insert {
+            tree.remove(key);
+        }
+    });
+    println!("{} keys left\n\n", tree.len());
 }
 
 
-fn main() {
-    const N: usize = 1_000_000;
+fn cores() -> usize { thread::available_parallelism().map_or(1, |n| n.get()) }
+
+
+fn scaling<K: Key>(rng: &mut impl Rng, max_threads: usize) {
+    const RECORDS: usize = 1_000_000;
     const M: i64 = 100_000_000;
-    let to_insert = random_vec(N, M);
-    let to_lookup = random_vec(100_000, M);
-    benchmark::<BTreeSet<i64>>("std::collections::BTreeSet", &to_insert, &to_lookup);
-    benchmark::<BTree<i64>>("BTree", &to_insert, &to_lookup);
-    benchmark::<RBTree<i64>>("RBTree", &to_insert, &to_lookup);
+    let load: Vec<K> = random_vec(rng, RECORDS, M).into_iter().map(K::from_i64).collect();
+    let lookups: Vec<K> = random_vec(rng, 200_000, M).into_iter().map(K::from_i64).collect();
+    let updates: Vec<K> = random_vec(rng, 100_000, M).into_iter().map(K::from_i64).collect();
+    let mut threads: Vec<usize> = (0..).map(|i| 1 << i).take_while(|&n| n < max_threads).collect();
+    threads.push(max_threads);
+    println!("Keys: {}, {} lookups per thread, one thread updating", K::NAME, lookups.len());
+    if max_threads >= cores() {
+        println!("Note: {} readers and the writer are more threads than the {} cores, \
+                  so the last runs measure time sharing as well", max_threads, cores());
+    }
+    println!();
+
+    let build = |set: &dyn SharedSet<K>| {
+        for key in &load {
+            set.insert(key.clone());
+        }
+    };
+    let set = ConcurrentSet::new(PersistentBTree::new());
+    build(&set);
+    scaling_benchmark("ConcurrentSet<PersistentBTree>", &set, &threads, &lookups, &updates);
+    let set = ConcurrentSet::new(PersistentRBTree::new());
+    build(&set);
+    scaling_benchmark("ConcurrentSet<PersistentRBTree>", &set, &threads, &lookups, &updates);
+    let set = RwLock::new(BTree::new());
+    build(&set);
+    scaling_benchmark("RwLock<BTree>", &set, &threads, &lookups, &updates);
+    let set = RwLock::new(RBTree::new());
+    build(&set);
+    scaling_benchmark("RwLock<RBTree>", &set, &threads, &lookups, &updates);
+}
+
+
+/// Runs the lookups on each number of threads, while one more thread
+/// inserts and removes the updates over and over, and prints the lookup
+/// throughput against that of one thread.
+fn scaling_benchmark<K: Key, S: SharedSet<K>>(name: &str, set: &S, threads: &[usize],
+                                              lookups: &[K], updates: &[K]) {
+    println!("{} ({} keys):", name, set.len());
+    let mut base = None;
+    for &n in threads {
+        let done = AtomicBool::new(false);
+        let (elapsed, writes, hits) = thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut writes = 0;
+                for key in updates.iter().cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if !set.insert(key.clone()) {
+                        set.remove(key);
+                    }
+                    writes += 1;
+                }
+                writes
+            });
+            let start = Instant::now();
+            let readers: Vec<_> = (0..n).map(|_| scope.spawn(|| {
+                lookups.iter().filter(|key| set.contains(key)).count()
+            })).collect();
+            let hits: usize = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+            let elapsed = start.elapsed();
+            done.store(true, Ordering::Relaxed);
+            (elapsed, writer.join().unwrap(), hits)
+        });
+        let throughput = (n * lookups.len()) as f64 / elapsed.as_secs_f64();
+        let base = *base.get_or_insert(throughput);
+        println!("{:>3} readers: {:>6.2} M lookups/s, {:.2}x one reader, {} updates, {} hits",
+                 n, throughput / 1e6, throughput / base, writes, hits);
+    }
+    println!("\n");
+}
+
+
+fn parallel_ycsb<K: Key>(rng: &mut impl Rng, threads: usize, args: &[String]) {
+    const RECORDS: usize = 1_000_000;
+    const OPERATIONS: usize = 2_000_000;
+    let workloads = if args.is_empty() {
+        Workload::presets().to_vec()
+    } else {
+        args.iter().map(|arg| Workload::parse(arg).unwrap_or_else(|e| exit_with(&e))).collect()
+    };
+
+    for workload in &workloads {
+        println!("Workload {}: {} records, {} operations on {} threads, {} keys\n",
+                 workload, RECORDS, OPERATIONS, threads, K::NAME);
+        let (load, ops) = workload.generate(rng, RECORDS, OPERATIONS);
+        warn_of_collisions::<K>(load.iter().chain(ops.iter().map(Op::key)));
+        let load: Vec<K> = load.into_iter().map(K::from_i64).collect();
+        let ops: Vec<Op<K>> = ops.into_iter().map(Op::with_key).collect();
+        let streams = parallel::split(&ops, threads);
+        run_shared::<K, BTree<K>, _>("Mutex<BTree>", Mutex::new, &load, &streams);
+        run_shared::<K, BTree<K>, _>("RwLock<BTree>", RwLock::new, &load, &streams);
+        run_shared::<K, RBTree<K>, _>("RwLock<RBTree>", RwLock::new, &load, &streams);
+        run_shared::<K, PersistentBTree<K>, _>("ConcurrentSet<PersistentBTree>", ConcurrentSet::new,
+                                               &load, &streams);
+        if workload.scan > 0 {
+            println!("The sharded sets are left out, as their scans would see one shard.\n");
+            continue;
+        }
+        let streams = parallel::route(&ops, threads);
+        run_sharded::<K, BTree<K>>("sharded BTree", &load, &streams);
+        run_sharded::<K, RBTree<K>>("sharded RBTree", &load, &streams);
+    }
 }
 
 
-fn random_vec(size: usize, range: i64) -> Vec<i64> {
+/// Loads a set of type `A`, then shares it wrapped by `wrap`.
+fn run_shared<K: Key, A: IntSet<K>, S: SharedSet<K>>(name: &str, wrap: fn(A) -> S, load: &[K],
+                                                     streams: &[Vec<Op<K>>]) {
+    let mut set = A::empty();
+    for key in load {
+        set.put(key.clone());
+    }
+    let set = wrap(set);
+    let report = parallel::run_shared(&set, streams);
+    println!("{} ({} keys after the run):\n{}\n", name, set.len(), report);
+}
+
+
+fn run_sharded<K: Key, A: IntSet<K> + Send>(name: &str, load: &[K], streams: &[Vec<Op<K>>]) {
+    let mut shards: Vec<A> = streams.iter().map(|_| A::empty()).collect();
+    for key in load {
+        shards[parallel::shard_of(key, streams.len())].put(key.clone());
+    }
+    let report = parallel::run_sharded(&mut shards, streams);
+    let len: usize = shards.iter().map(IntSet::len).sum();
+    println!("{} ({} keys after the run):\n{}\n", name, len, report);
+}
+
+
+/// Writes the operations a workload sends to a set, including the initial
+/// load, to a trace file.
+fn record(rng: &mut impl Rng, workload: &str, path: &str) {
+    const RECORDS: usize = 100_000;
+    const OPERATIONS: usize = 1_000_000;
+    let workload = Workload::parse(workload).unwrap_or_else(|e| exit_with(&e));
+    let (load, ops) = workload.generate(rng, RECORDS, OPERATIONS);
+    let mut recorder = trace::Recorder::<BTreeSet<i64>>::empty();
+    for &key in &load {
+        recorder.put(key);
+    }
+    workload::run(&mut recorder, &ops);
+    let recorded = recorder.into_ops();
+    trace::write_file(path, &recorded).unwrap_or_else(|e| exit_with(&format!("{}: {}", path, e)));
+    println!("Recorded {} operations of workload {} to {}", recorded.len(), workload, path);
+}
+
+
+fn replay<K: Key>(perf: &mut Option<perf::Counters>, path: &str) {
+    let ops = trace::read_file(path).unwrap_or_else(|e| exit_with(&format!("{}: {}", path, e)));
+    println!("Replaying {} operations from {} with {} keys\n", ops.len(), path, K::NAME);
+    warn_of_collisions::<K>(ops.iter().map(Op::key));
+    let ops: Vec<Op<K>> = ops.into_iter().map(Op::with_key).collect();
+    run_workload::<K, BTreeSet<K>>("std::collections::BTreeSet", perf, &[], &ops);
+    run_workload::<K, BTree<K>>("BTree", perf, &[], &ops);
+    run_workload::<K, RBTree<K>>("RBTree", perf, &[], &ops);
+}
+
+
+fn dot(tree: &str, keys: &[String]) {
+    let keys: Vec<i64> = keys.iter()
+        .map(|key| key.parse().unwrap_or_else(|_| exit_with(&format!("invalid key: {}", key))))
+        .collect();
+    match tree {
+        "btree" => {
+            let mut tree = BTree::new();
+            for &key in &keys {
+                tree.insert(key);
+            }
+            print!("{}", tree.to_dot());
+        }
+        "rbtree" => {
+            let mut tree = RBTree::new();
+            for &key in &keys {
+                tree.insert(key);
+            }
+            print!("{}", tree.to_dot());
+        }
+        _ => exit_with(&format!("unknown tree: {}, expected btree or rbtree", tree)),
+    }
+}
+
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+
+fn run_workload<K: Key, A: IntSet<K>>(name: &str, perf: &mut Option<perf::Counters>,
+                                      load: &[K], ops: &[Op<K>]) {
+    let mut set = A::empty();
+    for key in load {
+        set.put(key.clone());
+    }
+    let report = counted(perf, ops.len(), || workload::run(&mut set, ops));
+    println!("{} ({} keys after the run):\n{}", name, set.len(), report);
+    set.report();
+}
+
+
+/// Runs `f`, printing the hardware counters per operation if they are enabled.
+fn counted<T, F: FnOnce() -> T>(perf: &mut Option<perf::Counters>, ops: usize, f: F) -> T {
+    match *perf {
+        None => f(),
+        Some(ref mut counters) => {
+            let (result, sample) = counters.measure(f);
+            println!("{}", sample.per_op(ops));
+            result
+        }
+    }
+}
+
+
+fn random_vec<R: Rng>(rng: &mut R, size: usize, range: i64) -> Vec<i64> {
     let mut result = Vec::with_capacity(size);
     for _ in 0..size {
-        result.push(rand::random::<i64>() % range);
+        result.push(rng.gen::<i64>() % range);
     }
     result
 }
@@ -100,24 +557,132 @@ fn timeit<T, F: FnOnce() -> T>(message: &str, f: F) -> T {
 }
 
 
-fn benchmark<A: IntSet>(name: &str, to_insert: &[i64], to_lookup: &[i64]) {
-    let mut tree = A::empty();
+/// The set operations of `BTreeSet`, which the trees have under the same
+/// names.
+trait SetAlgebra<K: Key>: IntSet<K> {
+    /// The number of keys the operation yields, taking `self` as the first
+    /// set, so the iterators cannot be optimised away.
+    fn count(&self, other: &Self, op: SetOp) -> usize;
+    fn is_subset(&self, other: &Self) -> bool;
+}
 
-    timeit(&format!("Inserting {} random keys into {}", to_insert.len(), name), || {
-        for &key in to_insert {
-            tree.put(key);
+
+#[derive(Clone, Copy, Debug)]
+enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+}
+
+
+macro_rules! impl_set_algebra {
+    ($($set:ident),*) => {$(
+        impl<K: Key> SetAlgebra<K> for $set<K> {
+            fn count(&self, other: &Self, op: SetOp) -> usize {
+                match op {
+                    SetOp::Union => self.union(other).count(),
+                    SetOp::Intersection => self.intersection(other).count(),
+                    SetOp::Difference => self.difference(other).count(),
+                    SetOp::SymmetricDifference => self.symmetric_difference(other).count(),
+                }
+            }
+
+            fn is_subset(&self, other: &Self) -> bool { $set::is_subset(self, other) }
         }
+    )*};
+}
+
+impl_set_algebra!(BTreeSet, BTree, RBTree);
+
+
+fn set_op_benchmark<K: Key, A: SetAlgebra<K>>(name: &str, a: &[K], b: &[K]) {
+    let build = |keys: &[K]| {
+        let mut set = A::empty();
+        for key in keys {
+            set.put(key.clone());
+        }
+        set
+    };
+    let (a, b) = (build(a), build(b));
+    println!("{}:", name);
+    let ops = [SetOp::Union, SetOp::Intersection, SetOp::Difference, SetOp::SymmetricDifference];
+    for &op in &ops {
+        let count = timeit(&format!("{:?} of the first set with the second", op), || a.count(&b, op));
+        let reverse = timeit(&format!("{:?} of the second set with the first", op), || b.count(&a, op));
+        println!("keys = {}, {}\n", count, reverse);
+    }
+    let subset = timeit("Checking whether the second set is a subset of the first", || b.is_subset(&a));
+    println!("subset = {}\n\n", subset);
+}
+
+
+fn benchmark<K: Key, A: NearestKeys<K>>(name: &str, perf: &mut Option<perf::Counters>,
+                                   to_insert: &[K], to_lookup: &[K]) {
+    let mut tree = A::empty();
+
+    let (_, allocs) = memory::measure(|| {
+        // Cloned before the clock starts, so that insertion does not pay for
+        // copying `String`s, but within the measurement, so that the memory
+        // the tree keeps counts as live.
+        let keys = to_insert.to_vec();
+        timeit(&format!("Inserting {} random keys into {}", to_insert.len(), name), || {
+            counted(perf, to_insert.len(), || {
+                for key in keys {
+                    tree.put(key);
+                }
+            })
+        })
     });
-    A::report();
+    tree.report();
+    if let Some(allocs) = allocs {
+        println!("Memory: {}", allocs);
+    }
+    let usage = tree.memory_usage();
+    if let Some(usage) = usage {
+        println!("Nodes:  {}", usage);
+    }
+    if allocs.is_some() || usage.is_some() {
+        println!();
+    }
 
     let mut hash = 0;
     timeit(&format!("Retrieving {} random keys", to_lookup.len()), || {
-        for &key in to_lookup {
-            hash += if tree.get(key) { 1 } else { 0 };
-        }
+        counted(perf, to_lookup.len(), || {
+            for key in to_lookup {
+                hash += if tree.get(key) { 1 } else { 0 };
+            }
+        })
     });
 
-    println!("hash = {}\n\n", hash);
-}
+    println!("hash = {}\n", hash);
 
+    let mut hash = 0;
+    timeit(&format!("Finding the floor of {} random keys", to_lookup.len()), || {
+        counted(perf, to_lookup.len(), || {
+            for key in to_lookup {
+                hash += tree.floor(key).map_or(0, Key::checksum);
+            }
+        })
+    });
+    timeit(&format!("Finding the ceiling of {} random keys", to_lookup.len()), || {
+        counted(perf, to_lookup.len(), || {
+            for key in to_lookup {
+                hash += tree.ceiling(key).map_or(0, Key::checksum);
+            }
+        })
+    });
+    println!("hash = {}\n", hash);
 
+    let len = tree.len();
+    let mut hash = 0;
+    timeit(&format!("Popping {} keys from alternate ends", len), || {
+        counted(perf, len, || {
+            for i in 0..len {
+                let key = if i % 2 == 0 { tree.pop_first() } else { tree.pop_last() };
+                hash += key.unwrap().checksum();
+            }
+        })
+    });
+    println!("hash = {}\n\n", hash);
+}
@@ -0,0 +1,164 @@
+//! Space accounting.
+//!
+//! With the `count-alloc` feature the global allocator counts every
+//! allocation, so that the benchmark can report what each structure
+//! actually asked the allocator for. `MemoryUsage` is the analytic
+//! counterpart computed by the trees themselves.
+
+
+use std::fmt;
+
+
+/// The analytic size of a tree: node count times node size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub nodes: usize,
+    pub node_size: usize,
+    /// Everything, including the node embedded in the tree handle, if any.
+    pub total: usize,
+    /// Bytes occupied by the keys themselves, not counting any heap
+    /// memory they own.
+    pub keys: usize,
+}
+
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let overhead = self.total - self.keys;
+        write!(f, "{} nodes of {} bytes: {} KiB, {} KiB of keys, {:.1}% overhead",
+               self.nodes, self.node_size, self.total / 1024, self.keys / 1024,
+               100.0 * overhead as f64 / self.total.max(1) as f64)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Total bytes requested, not counting frees.
+    pub allocated: usize,
+    pub allocations: usize,
+    pub live: usize,
+    pub peak_live: usize,
+}
+
+
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "allocated {} KiB in {} allocations, {} KiB live, {} KiB peak",
+               self.allocated / 1024, self.allocations, self.live / 1024, self.peak_live / 1024)
+    }
+}
+
+
+/// Runs `f` and returns the allocations it made, if they are counted.
+/// Live and peak bytes are relative to the start.
+pub fn measure<T, F: FnOnce() -> T>(f: F) -> (T, Option<AllocStats>) {
+    let start = counting::reset_peak();
+    let result = f();
+    let stats = match (start, counting::snapshot()) {
+        (Some(start), Some(end)) => Some(AllocStats {
+            allocated: end.allocated - start.allocated,
+            allocations: end.allocations - start.allocations,
+            live: end.live.saturating_sub(start.live),
+            peak_live: end.peak_live.saturating_sub(start.live),
+        }),
+        _ => None,
+    };
+    (result, stats)
+}
+
+
+#[cfg(feature = "count-alloc")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::AllocStats;
+
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+    static PEAK_LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counting;
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let result = System.alloc(layout);
+            if !result.is_null() {
+                allocated(layout.size());
+            }
+            result
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let result = System.alloc_zeroed(layout);
+            if !result.is_null() {
+                allocated(layout.size());
+            }
+            result
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let result = System.realloc(ptr, layout, new_size);
+            if !result.is_null() {
+                LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+                allocated(new_size);
+            }
+            result
+        }
+    }
+
+    fn allocated(size: usize) {
+        ALLOCATED.fetch_add(size, Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_LIVE.fetch_max(live, Ordering::Relaxed);
+    }
+
+    pub fn snapshot() -> Option<AllocStats> {
+        Some(AllocStats {
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            live: LIVE.load(Ordering::Relaxed),
+            peak_live: PEAK_LIVE.load(Ordering::Relaxed),
+        })
+    }
+
+    pub fn reset_peak() -> Option<AllocStats> {
+        PEAK_LIVE.store(LIVE.load(Ordering::Relaxed), Ordering::Relaxed);
+        snapshot()
+    }
+}
+
+
+#[cfg(not(feature = "count-alloc"))]
+mod counting {
+    use super::AllocStats;
+
+    pub fn snapshot() -> Option<AllocStats> { None }
+
+    pub fn reset_peak() -> Option<AllocStats> { None }
+}
+
+
+#[cfg(feature = "count-alloc")]
+#[test]
+fn counts_allocations() {
+    let (_, stats) = measure(|| {
+        let xs = vec![0u64; 1024];
+        let ys = vec![0u64; 1024];
+        drop(xs);
+        ys
+    });
+    let stats = stats.unwrap();
+    assert!(stats.allocations >= 2);
+    assert!(stats.allocated >= 2 * 8 * 1024);
+}
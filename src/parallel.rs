@@ -0,0 +1,201 @@
+//! Multi-threaded runs of workloads.
+//!
+//! Each worker thread runs its own stream of operations, either against a
+//! set all of them share or against a shard of its own, which holds the
+//! keys routed to that thread, as a request server partitioned by key
+//! would. Every operation is timed, so that the report has the latencies
+//! each thread saw besides the aggregate throughput.
+
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use concurrent::SharedSet;
+use key::Key;
+use workload::{self, Op};
+use super::IntSet;
+
+
+pub struct ThreadReport {
+    elapsed: Duration,
+    /// The latency of every operation in nanoseconds, sorted.
+    latencies: Vec<u64>,
+    pub hash: i64,
+}
+
+
+pub struct ParallelReport {
+    pub threads: Vec<ThreadReport>,
+    /// From starting the first thread to the last one finishing.
+    wall: Duration,
+}
+
+
+/// Splits the operations into a stream per thread, in order.
+pub fn split<K: Clone>(ops: &[Op<K>], threads: usize) -> Vec<Vec<Op<K>>> {
+    let chunk = ops.len().div_ceil(threads).max(1);
+    let mut streams: Vec<Vec<Op<K>>> = ops.chunks(chunk).map(<[Op<K>]>::to_vec).collect();
+    streams.resize(threads, Vec::new());
+    streams
+}
+
+
+/// Sends every operation to the thread that owns its key.
+pub fn route<K: Key>(ops: &[Op<K>], threads: usize) -> Vec<Vec<Op<K>>> {
+    let mut streams = vec![Vec::new(); threads];
+    for op in ops {
+        streams[shard_of(op.key(), threads)].push(op.clone());
+    }
+    streams
+}
+
+
+/// The shard of `key`, from its checksum. Scans stay within one shard, so
+/// they see only the keys of that shard in the range.
+pub fn shard_of<K: Key>(key: &K, shards: usize) -> usize {
+    let hash = (key.checksum() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    ((hash >> 32) % shards as u64) as usize
+}
+
+
+/// Runs each stream on a thread of its own against `set`.
+pub fn run_shared<K: Key, S: SharedSet<K>>(set: &S, streams: &[Vec<Op<K>>]) -> ParallelReport {
+    let start = Instant::now();
+    let threads = thread::scope(|scope| {
+        let workers: Vec<_> = streams.iter()
+            .map(|ops| scope.spawn(move || timed(ops, |op| workload::apply(set, op))))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    ParallelReport { threads, wall: start.elapsed() }
+}
+
+
+/// Runs each stream on a thread of its own against the shard with the
+/// same index.
+pub fn run_sharded<K: Key, A: IntSet<K> + Send>(shards: &mut [A], streams: &[Vec<Op<K>>])
+                                               -> ParallelReport {
+    assert_eq!(shards.len(), streams.len(), "Not a shard per stream");
+    let start = Instant::now();
+    let threads = thread::scope(|scope| {
+        let workers: Vec<_> = shards.iter_mut().zip(streams)
+            .map(|(shard, ops)| scope.spawn(move || timed(ops, |op| workload::apply(&mut *shard, op))))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    ParallelReport { threads, wall: start.elapsed() }
+}
+
+
+fn timed<K, F: FnMut(&Op<K>) -> i64>(ops: &[Op<K>], mut apply: F) -> ThreadReport {
+    let mut latencies = Vec::with_capacity(ops.len());
+    let mut hash = 0i64;
+    let start = Instant::now();
+    let mut last = start;
+    for op in ops {
+        hash = hash.wrapping_add(apply(op));
+        let now = Instant::now();
+        latencies.push((now - last).as_nanos() as u64);
+        last = now;
+    }
+    latencies.sort_unstable();
+    ThreadReport { elapsed: last - start, latencies, hash }
+}
+
+
+impl ThreadReport {
+    pub fn ops(&self) -> usize { self.latencies.len() }
+
+    /// The latency that `per_mille` of the operations did not exceed.
+    pub fn percentile(&self, per_mille: usize) -> u64 {
+        match self.latencies.len() {
+            0 => 0,
+            len => self.latencies[(len - 1) * per_mille / 1000],
+        }
+    }
+}
+
+
+impl ParallelReport {
+    pub fn ops(&self) -> usize { self.threads.iter().map(ThreadReport::ops).sum() }
+
+    pub fn hash(&self) -> i64 {
+        self.threads.iter().fold(0, |acc, thread| acc.wrapping_add(thread.hash))
+    }
+}
+
+
+impl fmt::Display for ParallelReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>9}: {:>9} ops, {:>8.3} Mops/s on {} threads",
+                 "total", self.ops(), mops(self.ops(), self.wall), self.threads.len())?;
+        for (i, thread) in self.threads.iter().enumerate() {
+            writeln!(f, "{:>9}: {:>9} ops, {:>8.3} Mops/s, latency p50 {} ns, p99 {} ns, \
+                         p99.9 {} ns, max {} ns",
+                     format!("thread {}", i), thread.ops(), mops(thread.ops(), thread.elapsed),
+                     thread.percentile(500), thread.percentile(990), thread.percentile(999),
+                     thread.percentile(1000))?;
+        }
+        writeln!(f, "hash = {}", self.hash())
+    }
+}
+
+
+fn mops(ops: usize, time: Duration) -> f64 {
+    let secs = time.as_secs_f64();
+    if secs == 0.0 { 0.0 } else { ops as f64 / secs / 1e6 }
+}
+
+
+/// The puts all run before the gets, so however the threads interleave,
+/// every get finds what it would on one thread.
+#[test]
+fn parallel_runs_agree_with_one_thread() {
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+    use btree::{BTree, PersistentBTree};
+    use concurrent::ConcurrentSet;
+    use rng::TestRng;
+    use workload::Workload;
+
+    let workload = Workload::parse("get=70,insert=30,dist=uniform").unwrap();
+    let (load, ops) = workload.generate(&mut TestRng::new(), 1000, 20_000);
+    let loaded = || {
+        let mut set = BTree::empty();
+        for &key in &load {
+            set.put(key);
+        }
+        set
+    };
+    let (gets, inserted): (Vec<Op>, Vec<Op>) =
+        ops.into_iter().partition(|op| matches!(op, Op::Get(_)));
+    let mut all = BTreeSet::new();
+    all.extend(load.iter().cloned());
+    all.extend(inserted.iter().map(|op| *op.key()));
+    let expected = gets.iter().filter(|op| all.contains(op.key())).count() as i64;
+
+    let mut reports = Vec::new();
+    for &threads in &[1, 3, 4] {
+        let set = Mutex::new(loaded());
+        run_shared(&set, &split(&inserted, threads));
+        reports.push(run_shared(&set, &split(&gets, threads)));
+        let set = ConcurrentSet::new(PersistentBTree::new());
+        for &key in &load {
+            set.insert(key);
+        }
+        run_shared(&set, &split(&inserted, threads));
+        reports.push(run_shared(&set, &split(&gets, threads)));
+        let mut shards: Vec<BTree<i64>> = (0..threads).map(|_| BTree::empty()).collect();
+        for &key in &load {
+            shards[shard_of(&key, threads)].put(key);
+        }
+        run_sharded(&mut shards, &route(&inserted, threads));
+        reports.push(run_sharded(&mut shards, &route(&gets, threads)));
+    }
+    for report in &reports {
+        assert_eq!(report.ops(), gets.len());
+        assert_eq!(report.hash(), expected);
+    }
+    assert_eq!(reports[3].threads.len(), 3);
+    assert!(reports[3].threads[0].percentile(500) <= reports[3].threads[0].percentile(1000));
+}
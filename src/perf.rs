@@ -0,0 +1,260 @@
+//! Hardware performance counters via Linux `perf_event_open`.
+//!
+//! Counters are opened for the current thread, user space only, which
+//! works with the default `perf_event_paranoid` of 2. Counters the CPU
+//! or the hypervisor does not provide are skipped and listed by
+//! `Counters::dropped`; if none can be opened at all, `Counters::open`
+//! returns the reason. A counter the kernel never scheduled during a
+//! measurement, as when too many are multiplexed, has no value.
+
+
+use std::fmt;
+
+
+const EVENTS: [(&str, u32, u64); 6] = [
+    ("cycles", sys::PERF_TYPE_HARDWARE, sys::PERF_COUNT_HW_CPU_CYCLES),
+    ("instructions", sys::PERF_TYPE_HARDWARE, sys::PERF_COUNT_HW_INSTRUCTIONS),
+    ("branch-misses", sys::PERF_TYPE_HARDWARE, sys::PERF_COUNT_HW_BRANCH_MISSES),
+    ("L1d-misses", sys::PERF_TYPE_HW_CACHE, sys::cache_miss(sys::PERF_COUNT_HW_CACHE_L1D)),
+    ("LLC-misses", sys::PERF_TYPE_HW_CACHE, sys::cache_miss(sys::PERF_COUNT_HW_CACHE_LL)),
+    ("dTLB-misses", sys::PERF_TYPE_HW_CACHE, sys::cache_miss(sys::PERF_COUNT_HW_CACHE_DTLB)),
+];
+
+
+pub struct Counters {
+    counters: Vec<(&'static str, sys::Counter)>,
+    /// The counters that could not be opened, with the reason.
+    dropped: Vec<(&'static str, String)>,
+}
+
+
+impl Counters {
+    pub fn open() -> Result<Counters, String> {
+        let mut counters = Vec::new();
+        let mut dropped = Vec::new();
+        for &(name, kind, config) in EVENTS.iter() {
+            match sys::Counter::open(kind, config) {
+                Ok(counter) => counters.push((name, counter)),
+                Err(e) => dropped.push((name, e)),
+            }
+        }
+        if counters.is_empty() {
+            let (_, e) = dropped.pop().expect("No events");
+            return Err(e);
+        }
+        Ok(Counters { counters, dropped })
+    }
+
+    pub fn dropped(&self) -> &[(&'static str, String)] { &self.dropped }
+
+    /// Counts the events that happen while running `f`.
+    pub fn measure<T, F: FnOnce() -> T>(&mut self, f: F) -> (T, Sample) {
+        for &mut (_, ref mut counter) in &mut self.counters {
+            counter.start();
+        }
+        let result = f();
+        for &mut (_, ref mut counter) in self.counters.iter_mut().rev() {
+            counter.stop();
+        }
+        let values = self.counters.iter()
+            .map(|&(name, ref counter)| (name, counter.value()))
+            .collect();
+        (result, Sample { values })
+    }
+}
+
+
+pub struct Sample {
+    /// `None` for the counters that were not scheduled.
+    values: Vec<(&'static str, Option<u64>)>,
+}
+
+
+impl Sample {
+    /// The count, if the counter is open and was scheduled.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values.iter().find(|&&(n, _)| n == name).and_then(|&(_, value)| value)
+    }
+
+    /// Displays the counts divided by the number of operations.
+    pub fn per_op(&self, ops: usize) -> PerOp<'_> { PerOp(self, ops) }
+}
+
+
+pub struct PerOp<'a>(&'a Sample, usize);
+
+
+impl<'a> fmt::Display for PerOp<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let PerOp(sample, ops) = *self;
+        write!(f, "per op:")?;
+        for &(name, value) in &sample.values {
+            match value {
+                Some(value) => write!(f, " {} {:.2}", name, value as f64 / ops.max(1) as f64)?,
+                None => write!(f, " {} n/a", name)?,
+            }
+        }
+        if let (Some(instructions), Some(cycles)) = (sample.get("instructions"), sample.get("cycles")) {
+            write!(f, ", IPC {:.2}", instructions as f64 / cycles.max(1) as f64)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem::size_of;
+    use libc::{self, c_int, c_ulong};
+
+    pub const PERF_TYPE_HARDWARE: u32 = 0;
+    pub const PERF_TYPE_HW_CACHE: u32 = 3;
+
+    pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    pub const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+    pub const PERF_COUNT_HW_CACHE_L1D: u64 = 0;
+    pub const PERF_COUNT_HW_CACHE_LL: u64 = 2;
+    pub const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+    const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+    const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+
+    const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1;
+    const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 2;
+
+    const FLAG_DISABLED: u64 = 1;
+    const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+    const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+    const PERF_FLAG_FD_CLOEXEC: c_ulong = 8;
+
+    const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+    const PERF_EVENT_IOC_DISABLE: c_ulong = 0x2401;
+    const PERF_EVENT_IOC_RESET: c_ulong = 0x2403;
+
+    pub const fn cache_miss(cache: u64) -> u64 {
+        cache | PERF_COUNT_HW_CACHE_OP_READ << 8 | PERF_COUNT_HW_CACHE_RESULT_MISS << 16
+    }
+
+    /// The first version of `struct perf_event_attr`, which every kernel
+    /// since 2.6.31 accepts.
+    #[repr(C)]
+    struct PerfEventAttr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+    }
+
+    pub struct Counter {
+        fd: c_int,
+    }
+
+    impl Counter {
+        pub fn open(kind: u32, config: u64) -> Result<Counter, String> {
+            let attr = PerfEventAttr {
+                kind,
+                size: size_of::<PerfEventAttr>() as u32,
+                config,
+                sample_period: 0,
+                sample_type: 0,
+                read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+                flags: FLAG_DISABLED | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV,
+                wakeup_events: 0,
+                bp_type: 0,
+                config1: 0,
+            };
+            let fd = unsafe {
+                libc::syscall(libc::SYS_perf_event_open, &attr as *const PerfEventAttr,
+                              0 as libc::pid_t, -1 as c_int, -1 as c_int, PERF_FLAG_FD_CLOEXEC)
+            };
+            if fd < 0 {
+                let error = io::Error::last_os_error();
+                return Err(format!("perf_event_open failed: {} (see /proc/sys/kernel/perf_event_paranoid)",
+                                   error));
+            }
+            Ok(Counter { fd: fd as c_int })
+        }
+
+        pub fn start(&mut self) {
+            unsafe {
+                libc::ioctl(self.fd, PERF_EVENT_IOC_RESET as _, 0);
+                libc::ioctl(self.fd, PERF_EVENT_IOC_ENABLE as _, 0);
+            }
+        }
+
+        pub fn stop(&mut self) {
+            unsafe { libc::ioctl(self.fd, PERF_EVENT_IOC_DISABLE as _, 0) };
+        }
+
+        /// The count, scaled up if the kernel had to multiplex the counter,
+        /// or `None` if it never got to run.
+        pub fn value(&self) -> Option<u64> {
+            let mut buf = [0u64; 3];
+            let size = size_of::<[u64; 3]>();
+            let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, size) };
+            let [value, enabled, running] = buf;
+            if read != size as isize || running == 0 {
+                return None;
+            }
+            Some((value as f64 * enabled as f64 / running as f64) as u64)
+        }
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    pub const PERF_TYPE_HARDWARE: u32 = 0;
+    pub const PERF_TYPE_HW_CACHE: u32 = 3;
+    pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    pub const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+    pub const PERF_COUNT_HW_CACHE_L1D: u64 = 0;
+    pub const PERF_COUNT_HW_CACHE_LL: u64 = 2;
+    pub const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+
+    pub const fn cache_miss(cache: u64) -> u64 { cache }
+
+    pub struct Counter;
+
+    impl Counter {
+        pub fn open(_kind: u32, _config: u64) -> Result<Counter, String> {
+            Err("hardware counters are only supported on Linux".to_string())
+        }
+        pub fn start(&mut self) {}
+        pub fn stop(&mut self) {}
+        pub fn value(&self) -> Option<u64> { None }
+    }
+}
+
+
+#[test]
+fn counts_instructions_or_explains_why_not() {
+    match Counters::open() {
+        Err(e) => assert!(!e.is_empty()),
+        Ok(mut counters) => {
+            assert!(counters.dropped().iter().all(|(_, e)| !e.is_empty()));
+            let (sum, sample) = counters.measure(|| (0..1_000_000u64).sum::<u64>());
+            assert_eq!(sum, 499_999_500_000);
+            // Missing if the counter was dropped or multiplexed out.
+            if let Some(instructions) = sample.get("instructions") {
+                assert!(instructions > 0);
+            }
+        }
+    }
+}
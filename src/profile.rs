@@ -0,0 +1,159 @@
+//! Phase timing for the tree internals.
+//!
+//! With the `profile` feature, `start` marks the beginning of an operation
+//! and every `lap` charges the time since the previous mark to a named
+//! phase, `tally` counts an event such as a rotation, and `count` bumps a
+//! histogram bucket, such as the number of splits at some tree level. The
+//! totals are kept per thread, so concurrent benchmarks do not interfere.
+//! Without the feature all of this compiles to nothing.
+
+
+use std::time::Duration;
+
+
+/// Everything recorded on one thread, in order of first appearance.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Total time and number of laps of each phase.
+    pub phases: Vec<(&'static str, Duration, u64)>,
+    pub events: Vec<(&'static str, u64)>,
+    pub counts: Vec<(&'static str, Vec<u64>)>,
+}
+
+
+#[cfg(feature = "profile")]
+mod imp {
+    use std::cell::RefCell;
+    use std::mem;
+    use std::time::Instant;
+    use super::Profile;
+
+    thread_local! {
+        static MARK: RefCell<Instant> = RefCell::new(Instant::now());
+        static PROFILE: RefCell<Profile> = RefCell::new(Profile::default());
+    }
+
+    pub fn start() {
+        MARK.with(|mark| *mark.borrow_mut() = Instant::now());
+    }
+
+    pub fn lap(phase: &'static str) {
+        let now = Instant::now();
+        let elapsed = MARK.with(|mark| now - mem::replace(&mut *mark.borrow_mut(), now));
+        PROFILE.with(|profile| {
+            let phases = &mut profile.borrow_mut().phases;
+            match phases.iter_mut().find(|&&mut (name, _, _)| name == phase) {
+                Some(&mut (_, ref mut total, ref mut laps)) => {
+                    *total += elapsed;
+                    *laps += 1;
+                }
+                None => phases.push((phase, elapsed, 1)),
+            }
+        });
+    }
+
+    pub fn tally(event: &'static str) {
+        PROFILE.with(|profile| {
+            let events = &mut profile.borrow_mut().events;
+            match events.iter_mut().find(|&&mut (name, _)| name == event) {
+                Some(&mut (_, ref mut n)) => *n += 1,
+                None => events.push((event, 1)),
+            }
+        });
+    }
+
+    pub fn count(counter: &'static str, bucket: usize) {
+        PROFILE.with(|profile| {
+            let counts = &mut profile.borrow_mut().counts;
+            let index = match counts.iter().position(|&(name, _)| name == counter) {
+                Some(index) => index,
+                None => {
+                    counts.push((counter, Vec::new()));
+                    counts.len() - 1
+                }
+            };
+            let buckets = &mut counts[index].1;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+        });
+    }
+
+    pub fn take() -> Profile {
+        PROFILE.with(|profile| mem::take(&mut *profile.borrow_mut()))
+    }
+}
+
+
+#[cfg(not(feature = "profile"))]
+mod imp {
+    use super::Profile;
+
+    #[inline(always)]
+    pub fn start() {}
+
+    #[inline(always)]
+    pub fn lap(_phase: &'static str) {}
+
+    #[inline(always)]
+    pub fn tally(_event: &'static str) {}
+
+    #[inline(always)]
+    pub fn count(_counter: &'static str, _bucket: usize) {}
+
+    pub fn take() -> Profile { Profile::default() }
+}
+
+
+pub use self::imp::{start, lap, tally, count, take};
+
+
+/// Prints what was recorded on this thread and resets it.
+pub fn report() {
+    let profile = take();
+    for &(name, total, laps) in &profile.phases {
+        println!("{:<20} {:>8} µs in {} laps", format!("{}:", name), total.as_micros(), laps);
+    }
+    for &(name, n) in &profile.events {
+        println!("{:<20} {}", format!("{}:", name), n);
+    }
+    for &(name, ref buckets) in &profile.counts {
+        let buckets: Vec<String> = buckets.iter().map(|n| n.to_string()).collect();
+        println!("{:<20} {}", format!("{}:", name), buckets.join(" "));
+    }
+    if !profile.phases.is_empty() || !profile.events.is_empty() || !profile.counts.is_empty() {
+        println!();
+    }
+}
+
+
+#[cfg(feature = "profile")]
+#[test]
+fn laps_tallies_and_counts_are_recorded() {
+    use std::thread;
+    use std::time::Duration;
+
+    take();
+    start();
+    thread::sleep(Duration::from_millis(2));
+    lap("sleep");
+    lap("nothing");
+    start();
+    thread::sleep(Duration::from_millis(2));
+    lap("sleep");
+    count("levels", 2);
+    count("levels", 0);
+    count("levels", 2);
+    tally("flip");
+    tally("flip");
+    let Profile { phases, events, counts } = take();
+    assert_eq!(events, vec![("flip", 2)]);
+    assert_eq!(counts, vec![("levels", vec![1, 0, 2])]);
+    assert_eq!(phases.len(), 2);
+    assert_eq!(phases[0].0, "sleep");
+    assert!(phases[0].1 >= Duration::from_millis(4));
+    assert_eq!(phases[0].2, 2);
+    assert_eq!(phases[1].0, "nothing");
+    assert!(take().phases.is_empty());
+}
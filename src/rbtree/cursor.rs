@@ -0,0 +1,152 @@
+//! Cursors over an `RBTree`. A cursor sits on a key and moves to its
+//! neighbours along the parent links, which takes amortised constant time
+//! per step. Past the last key and before the first one there is a ghost
+//! position without a key, where a seek that matches nothing ends up.
+
+
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::ptr;
+use compare::Comparator;
+use super::RBTree;
+use super::node::{self, Node};
+
+
+pub struct Cursor<'a, T: 'a> {
+    root: *const Node<T>,
+    /// Null at the ghost position.
+    current: *const Node<T>,
+    marker: PhantomData<&'a Node<T>>,
+}
+
+
+/// A cursor that can also insert keys next to the current one and remove
+/// it, without searching the tree again.
+pub struct CursorMut<'a, T: 'a, C: 'a> {
+    tree: &'a mut RBTree<T, C>,
+    current: *mut Node<T>,
+}
+
+
+impl<'a, T> Cursor<'a, T> {
+    pub(super) fn new(root: *const Node<T>, current: *const Node<T>) -> Cursor<'a, T> {
+        Cursor { root, current, marker: PhantomData }
+    }
+
+    /// The current key, or `None` at the ghost position.
+    pub fn key(&self) -> Option<&'a T> { unsafe { self.current.as_ref() }.map(|u| &u.key) }
+
+    /// Moves to the next key, or from the last key to the ghost position,
+    /// or from the ghost position to the first key.
+    pub fn move_next(&mut self) { self.current = unsafe { step_next(self.root, self.current) } }
+
+    /// Moves to the previous key, the other way round from `move_next`.
+    pub fn move_prev(&mut self) { self.current = unsafe { step_prev(self.root, self.current) } }
+}
+
+
+impl<'a, T, C: Comparator<T>> CursorMut<'a, T, C> {
+    pub(super) fn new(tree: &'a mut RBTree<T, C>, current: *const Node<T>) -> CursorMut<'a, T, C> {
+        CursorMut { tree, current: current as *mut Node<T> }
+    }
+
+    pub fn key(&self) -> Option<&T> { unsafe { self.current.as_ref() }.map(|u| &u.key) }
+
+    pub fn move_next(&mut self) {
+        self.current = unsafe { step_next(self.tree.root, self.current) as *mut Node<T> };
+    }
+
+    pub fn move_prev(&mut self) {
+        self.current = unsafe { step_prev(self.tree.root, self.current) as *mut Node<T> };
+    }
+
+    /// Inserts `key` right before the current key, or as the last key at
+    /// the ghost position. The cursor stays where it is. Gives the key back
+    /// if it does not belong there in the order of the tree.
+    pub fn insert_before(&mut self, key: T) -> Result<(), T> {
+        let prev = unsafe { step_prev(self.tree.root, self.current) };
+        self.insert_between(prev, self.current, key)
+    }
+
+    /// Inserts `key` right after the current key, or as the first key at
+    /// the ghost position.
+    pub fn insert_after(&mut self, key: T) -> Result<(), T> {
+        let next = unsafe { step_next(self.tree.root, self.current) };
+        self.insert_between(self.current, next, key)
+    }
+
+    /// Removes the current key and moves on to the next one.
+    pub fn remove_current(&mut self) -> Option<T> {
+        if self.current.is_null() {
+            return None;
+        }
+        let (root, key, next) = unsafe { node::remove_node(self.current) };
+        self.tree.root = root;
+        self.tree.len -= 1;
+        self.current = next;
+        Some(key)
+    }
+
+    fn insert_between(&mut self, before: *const Node<T>, after: *const Node<T>, key: T)
+                      -> Result<(), T> {
+        let cmp = &self.tree.cmp;
+        unsafe {
+            if !before.is_null() && !cmp.less(&(*before).key, &key)
+                || !after.is_null() && !cmp.less(&key, &(*after).key) {
+                return Err(key);
+            }
+            if !before.is_null() {
+                node::attach_after(before as *mut Node<T>, key);
+            } else if !after.is_null() {
+                node::attach_before(after as *mut Node<T>, key);
+            } else {
+                self.tree.root = Node::new_root(key);
+            }
+            self.tree.root = node::root_of(self.tree.root);
+        }
+        self.tree.len += 1;
+        Ok(())
+    }
+}
+
+
+/// The first node with a key at or after `bound`, or null.
+pub(super) unsafe fn lower_bound<T, Q, C>(root: *const Node<T>, bound: Bound<&Q>, cmp: &C)
+                                          -> *const Node<T>
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    if root.is_null() { ptr::null() } else { (*root).seek(bound, cmp) }
+}
+
+
+/// The last node with a key at or before `bound`, or null.
+pub(super) unsafe fn upper_bound<T, Q, C>(root: *const Node<T>, bound: Bound<&Q>, cmp: &C)
+                                          -> *const Node<T>
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let after = match bound {
+        Bound::Unbounded => ptr::null(),
+        Bound::Included(key) => lower_bound(root, Bound::Excluded(key), cmp),
+        Bound::Excluded(key) => lower_bound(root, Bound::Included(key), cmp),
+    };
+    step_prev(root, after)
+}
+
+
+unsafe fn step_next<T>(root: *const Node<T>, u: *const Node<T>) -> *const Node<T> {
+    match (u.is_null(), root.is_null()) {
+        (false, _) => node::next(u),
+        (true, false) => node::first(root as *mut Node<T>),
+        (true, true) => ptr::null(),
+    }
+}
+
+
+unsafe fn step_prev<T>(root: *const Node<T>, u: *const Node<T>) -> *const Node<T> {
+    match (u.is_null(), root.is_null()) {
+        (false, _) => node::prev(u),
+        (true, false) => node::last(root as *mut Node<T>),
+        (true, true) => ptr::null(),
+    }
+}
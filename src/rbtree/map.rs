@@ -0,0 +1,295 @@
+//! An ordered map on top of `RBTree`, which stores every entry as a
+//! `KeyValue` ordered by its key.
+
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::mem;
+use compare::{ByKey, Comparator, KeyValue, Natural, Query};
+use super::RBTree;
+use super::node::{self, Node, Range};
+
+
+#[derive(Debug)]
+pub struct Map<K, V, C = Natural> {
+    tree: RBTree<KeyValue<K, V>, ByKey<C>>,
+}
+
+
+impl<K: Ord, V> Map<K, V> {
+    pub fn new() -> Map<K, V> { Map::with_comparator(Natural) }
+}
+
+
+impl<K, V, C: Comparator<K>> Map<K, V, C> {
+    pub fn with_comparator(cmp: C) -> Map<K, V, C> {
+        Map { tree: RBTree::with_comparator(ByKey(cmp)) }
+    }
+
+    pub fn len(&self) -> usize { self.tree.len }
+    pub fn is_empty(&self) -> bool { self.tree.len == 0 }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let u = unsafe { node::search(self.tree.root, Query::new(key), &self.tree.cmp) }.ok()?;
+        Some(unsafe { &(*u).key.value })
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let u = unsafe { node::search(self.tree.root, Query::new(key), &self.tree.cmp) }.ok()?;
+        Some(unsafe { &mut (*u).key.value })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of `key`, returning the old one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let tree = &mut self.tree;
+        let (root, removed) = unsafe { node::remove(tree.root, Query::new(key), &tree.cmp)? };
+        tree.root = root;
+        tree.len -= 1;
+        Some(removed.value)
+    }
+
+    /// The entry of `key`, found in a single descent. A vacant entry keeps
+    /// the node to hang the new leaf from, so inserting it does not search
+    /// again.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, C> {
+        match unsafe { node::search(self.tree.root, Query::new(&key), &self.tree.cmp) } {
+            Ok(u) => Entry::Occupied(OccupiedEntry { map: self, key, u }),
+            Err(parent) => Entry::Vacant(VacantEntry { map: self, key, parent }),
+        }
+    }
+
+    /// The entries in the order of the keys.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter { range: self.tree.iter() }
+    }
+
+    pub fn check_invariants(&self) where K: fmt::Debug, V: fmt::Debug {
+        self.tree.check_invariants()
+    }
+}
+
+
+impl<K, V, C: Comparator<K> + Default> Default for Map<K, V, C> {
+    fn default() -> Self { Map::with_comparator(C::default()) }
+}
+
+
+pub enum Entry<'a, K: 'a, V: 'a, C: 'a> {
+    Occupied(OccupiedEntry<'a, K, V, C>),
+    Vacant(VacantEntry<'a, K, V, C>),
+}
+
+
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, C: 'a> {
+    map: &'a mut Map<K, V, C>,
+    /// The key passed to `entry`, which equals the one in the map.
+    key: K,
+    u: *mut Node<KeyValue<K, V>>,
+}
+
+
+pub struct VacantEntry<'a, K: 'a, V: 'a, C: 'a> {
+    map: &'a mut Map<K, V, C>,
+    key: K,
+    /// Where `node::search` stopped.
+    parent: (*mut Node<KeyValue<K, V>>, bool),
+}
+
+
+impl<'a, K, V, C: Comparator<K>> Entry<'a, K, V, C> {
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V where V: Default { self.or_insert_with(V::default) }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            vacant => vacant,
+        }
+    }
+}
+
+
+impl<'a, K, V, C: Comparator<K>> OccupiedEntry<'a, K, V, C> {
+    pub fn key(&self) -> &K { unsafe { &(*self.u).key.key } }
+    pub fn get(&self) -> &V { unsafe { &(*self.u).key.value } }
+    pub fn get_mut(&mut self) -> &mut V { unsafe { &mut (*self.u).key.value } }
+    pub fn into_mut(self) -> &'a mut V { unsafe { &mut (*self.u).key.value } }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> V { mem::replace(self.get_mut(), value) }
+
+    /// Removes the entry, returning its value.
+    pub fn remove(self) -> V { self.map.remove(&self.key).unwrap() }
+}
+
+
+impl<'a, K, V, C: Comparator<K>> VacantEntry<'a, K, V, C> {
+    pub fn key(&self) -> &K { &self.key }
+    pub fn into_key(self) -> K { self.key }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { map, key, parent: (parent, left) } = self;
+        let tree = &mut map.tree;
+        let kv = KeyValue { key, value };
+        let u = if parent.is_null() {
+            tree.root = Node::new_root(kv);
+            tree.root
+        } else {
+            ::profile::start();
+            unsafe {
+                let u = node::attach(parent, left, kv);
+                tree.root = node::root_of(tree.root);
+                u
+            }
+        };
+        tree.len += 1;
+        unsafe { &mut (*u).key.value }
+    }
+}
+
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    range: Range<'a, KeyValue<K, V>>,
+}
+
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.range.next().map(|kv| (&kv.key, &kv.value))
+    }
+}
+
+
+#[test]
+fn map_counts_words() {
+    use std::collections::BTreeMap;
+    let text = "the quick brown fox jumps over the lazy dog the end";
+    let mut map = Map::new();
+    let mut model = BTreeMap::new();
+    for _ in 0..50 {
+        for (i, word) in text.split(' ').enumerate() {
+            *map.entry((word, i % 7)).or_insert(0) += 1;
+            *model.entry((word, i % 7)).or_insert(0) += 1;
+        }
+    }
+    map.check_invariants();
+    assert_eq!(map.len(), model.len());
+    assert!(map.iter().eq(model.iter()));
+}
+
+
+#[test]
+fn map_entry() {
+    let mut map = Map::new();
+    assert_eq!(*map.entry(1).or_insert_with(|| 10), 10);
+    assert_eq!(*map.entry(1).and_modify(|v| *v += 1).or_insert(0), 11);
+    assert_eq!(*map.entry(2).and_modify(|v| *v += 1).or_default(), 0);
+    assert_eq!(map.entry(3).key(), &3);
+    match map.entry(1) {
+        Entry::Occupied(mut entry) => {
+            assert_eq!(entry.insert(20), 11);
+            assert_eq!(entry.remove(), 20);
+        }
+        Entry::Vacant(_) => panic!("1 is in the map"),
+    }
+    match map.entry(1) {
+        Entry::Vacant(entry) => assert_eq!(entry.into_key(), 1),
+        Entry::Occupied(_) => panic!("1 was removed"),
+    }
+    assert_eq!(map.insert(2, 5), Some(0));
+    assert_eq!(map.insert(4, 6), None);
+    assert_eq!(map.get(&2), Some(&5));
+    assert_eq!(map.remove(&4), Some(6));
+    assert!(!map.contains_key(&4));
+    assert_eq!(map.len(), 1);
+
+    // Ascending and descending keys split nodes at either end.
+    let mut map = Map::new();
+    for i in (0..500).chain((-500..0).rev()) {
+        *map.entry(i).or_insert(0) += i;
+        *map.entry(i).or_insert(0) += 1;
+    }
+    map.check_invariants();
+    assert!(map.iter().map(|(&k, &v)| (k, v)).eq((-500..500).map(|i| (i, i + 1))));
+
+    // Owned keys are looked up by their borrowed form.
+    let mut map = Map::new();
+    map.insert("one".to_string(), 1);
+    *map.get_mut("one").unwrap() += 1;
+    assert_eq!(map.get("one"), Some(&2));
+    assert!(!map.contains_key("two"));
+    assert_eq!(map.remove("one"), Some(2));
+    assert!(map.is_empty());
+}
+
+
+#[cfg(test)]
+proptest! {
+    /// Every insertion through a vacant entry must return the place the key
+    /// ended up in after the splits, or the sums go wrong.
+    #[test]
+    fn map_matches_btreemap(ops in ::proptest::collection::vec((0..3u8, -300i64..300), 0..1000)) {
+        use std::collections::BTreeMap;
+        let mut map = Map::new();
+        let mut model = BTreeMap::new();
+        for (kind, key) in ops {
+            match kind {
+                0 => *map.entry(key).or_insert(0) += key,
+                1 => prop_assert_eq!(map.remove(&key), model.remove(&key)),
+                _ => prop_assert_eq!(map.get(&key), model.get(&key)),
+            }
+            if kind == 0 {
+                *model.entry(key).or_insert(0) += key;
+            }
+        }
+        map.check_invariants();
+        prop_assert!(map.iter().eq(model.iter()));
+    }
+}
@@ -1,80 +1,603 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::mem::{size_of, swap};
+use std::ops::{Bound, RangeBounds};
 use std::ptr;
 
 
 mod node;
+mod map;
+mod cursor;
+mod persistent;
 
 
-use super::IntSet;
+use super::{IntSet, NearestKeys};
+use compare::{Comparator, Natural};
+use set_ops::{self, Difference, Intersection, SortedSet, SymmetricDifference, Union};
+use key::Key;
 use self::node::Node;
+use memory::MemoryUsage;
 
 
-pub static mut SEARCH_TIME: u64 = 0;
-pub static mut FIXUP_TIME: u64 = 0;
+pub use self::cursor::{Cursor, CursorMut};
+pub use self::map::{Entry, Iter, Map, OccupiedEntry, VacantEntry};
+pub use self::node::Range;
+pub use self::persistent::PersistentRBTree;
 
 
+/// A set ordered by the comparator `C`, which defaults to the keys' `Ord`.
 #[derive(Debug)]
-pub struct RBTree<T: Ord> {
+pub struct RBTree<T, C = Natural> {
     root: *mut Node<T>,
+    len: usize,
+    cmp: C,
 }
 
 
-impl<T: Ord> Drop for RBTree<T> {
+impl<T, C> Drop for RBTree<T, C> {
     fn drop(&mut self) {
-        if !self.root.is_null() {
-            unsafe { Box::from_raw(self.root); }
-        }
+        unsafe { node::destroy(self.root) }
     }
 }
 
 
+// The tree owns its nodes, and the raw pointers only link them, as the
+// pointers of a `Box` would.
+unsafe impl<T: Send, C: Send> Send for RBTree<T, C> {}
+unsafe impl<T: Sync, C: Sync> Sync for RBTree<T, C> {}
+
+
 impl<T: Ord> RBTree<T> {
-    pub fn new() -> RBTree<T> { RBTree { root: ptr::null_mut() } }
+    pub fn new() -> RBTree<T> { RBTree::with_comparator(Natural) }
+}
+
+
+impl<T, C: Comparator<T>> RBTree<T, C> {
+    pub fn with_comparator(cmp: C) -> RBTree<T, C> {
+        RBTree { root: ptr::null_mut(), len: 0, cmp }
+    }
+
+    pub fn comparator(&self) -> &C { &self.cmp }
+
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
 
-    pub fn contains(&self, key: T) -> bool {
+    pub fn contains<Q>(&self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// The key in the set that compares equal to `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
         if self.root.is_null() {
-            return false
+            return None
         }
         let root = unsafe { &(*self.root) };
-        root.contains(key)
+        root.get(key, &self.cmp)
     }
 
-    pub fn insert(&mut self, key: T) {
+    pub fn insert(&mut self, key: T) -> bool {
         if self.root.is_null() {
             self.root = Node::new_root(key);
-            return;
+            self.len = 1;
+            return true;
         }
-        ::tick();
-        unsafe {
-            (*self.root).insert(key);
+        ::profile::start();
+        let inserted = unsafe {
+            let inserted = (*self.root).insert(key, &self.cmp);
             let parent = (*self.root).parent;
             if !parent.is_null() {
                 self.root = parent;
             }
+            inserted
         };
+        self.len += inserted as usize;
+        inserted
     }
-}
 
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.take(key).is_some()
+    }
 
-impl IntSet for RBTree<i64> {
-    fn empty() -> Self { RBTree::new() }
+    /// Removes and returns the key in the set that compares equal to `key`.
+    pub fn take<Q>(&mut self, key: &Q) -> Option<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let (root, removed) = unsafe { node::remove(self.root, key, &self.cmp)? };
+        self.root = root;
+        self.len -= 1;
+        Some(removed)
+    }
+
+    pub fn first(&self) -> Option<&T> { self.nearest::<T>(Bound::Unbounded, false) }
+    pub fn last(&self) -> Option<&T> { self.nearest::<T>(Bound::Unbounded, true) }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.pop(unsafe { node::first(self.root) })
+    }
+
+    pub fn pop_last(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.pop(unsafe { node::last(self.root) })
+    }
+
+    /// The greatest key that is not greater than `key`.
+    pub fn floor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.nearest(Bound::Included(key), true)
+    }
+
+    /// The least key that is not less than `key`.
+    pub fn ceiling<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.nearest(Bound::Included(key), false)
+    }
+
+    /// The greatest key less than `key`.
+    pub fn predecessor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.nearest(Bound::Excluded(key), true)
+    }
+
+    /// The least key greater than `key`.
+    pub fn successor<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.nearest(Bound::Excluded(key), false)
+    }
 
-    fn get(&self, key: i64) -> bool { self.contains(key) }
+    /// The first key at or after `bound`, or with `before`, the last key at
+    /// or before it.
+    fn nearest<Q>(&self, bound: Bound<&Q>, before: bool) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let root = unsafe { self.root.as_ref()? };
+        let u = if before { root.seek_back(bound, &self.cmp) } else { root.seek(bound, &self.cmp) };
+        unsafe { u.as_ref() }.map(|u| &u.key)
+    }
 
-    fn put(&mut self, key: i64) { self.insert(key) }
+    fn pop(&mut self, u: *mut Node<T>) -> Option<T> {
+        let (root, key, _) = unsafe { node::remove_node(u) };
+        self.root = root;
+        self.len -= 1;
+        Some(key)
+    }
 
-    fn report() {
+    /// Moves the keys at and after `key` into a new tree. The split itself
+    /// takes logarithmic time, but the lengths are found by counting the
+    /// keys of the piece with the smaller black height, as nodes do not
+    /// record the sizes of their subtrees. That takes time linear in its
+    /// size, which near the middle is linear in the length of the tree.
+    pub fn split_off<Q>(&mut self, key: &Q) -> RBTree<T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q> + Clone
+    {
+        let (left, right) = unsafe { node::split(self.root, key, &self.cmp) };
+        let mut other = RBTree { root: right, len: 0, cmp: self.cmp.clone() };
+        self.root = left;
+        let len = self.len;
+        unsafe {
+            if node::black_height(left) < node::black_height(right) {
+                self.len = node::count(left);
+                other.len = len - self.len;
+            } else {
+                other.len = node::count(right);
+                self.len = len - other.len;
+            }
+        }
+        other
+    }
+
+    /// Moves all keys of `other` into this tree. If all keys of one tree
+    /// come before those of the other, the trees are joined in time
+    /// logarithmic in their sizes; otherwise the keys move one by one.
+    pub fn append(&mut self, other: &mut RBTree<T, C>) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            swap(&mut self.root, &mut other.root);
+            swap(&mut self.len, &mut other.len);
+            return;
+        }
         unsafe {
-            println!("Search: {} µs\nFixup:  {} µs\n", SEARCH_TIME / 1000, FIXUP_TIME / 1000);
-            SEARCH_TIME = 0;
-            FIXUP_TIME = 0;
+            let (first, last) = (node::first(self.root), node::last(self.root));
+            if self.cmp.less(&(*node::last(other.root)).key, &(*first).key) {
+                swap(&mut self.root, &mut other.root);
+            } else if !self.cmp.less(&(*last).key, &(*node::first(other.root)).key) {
+                let mut cursor = other.lower_bound_mut::<T>(Bound::Unbounded);
+                while let Some(key) = cursor.remove_current() {
+                    self.insert(key);
+                }
+                return;
+            }
+            let (root, key, _) = node::remove_node(node::last(self.root));
+            self.root = node::join(root, Node::new_root(key), other.root);
+        }
+        other.root = ptr::null_mut();
+        self.len += other.len;
+        other.len = 0;
+    }
+
+    /// The keys in order.
+    pub fn iter(&self) -> Range<'_, T> { self.range::<T, _>(..) }
+
+    /// The keys in either tree, in order.
+    pub fn union<'a>(&'a self, other: &'a Self) -> Union<'a, T, Self> { Union::new(self, other) }
+
+    /// The keys in both trees, in order.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> Intersection<'a, T, Self> {
+        Intersection::new(self, other)
+    }
+
+    /// The keys of this tree that are not in `other`, in order.
+    pub fn difference<'a>(&'a self, other: &'a Self) -> Difference<'a, T, Self> {
+        Difference::new(self, other)
+    }
+
+    /// The keys in exactly one of the trees, in order.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self) -> SymmetricDifference<'a, T, Self> {
+        SymmetricDifference::new(self, other)
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool { set_ops::is_subset(self, other) }
+    pub fn is_disjoint(&self, other: &Self) -> bool { set_ops::is_disjoint(self, other) }
+
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>
+    {
+        Range::new(self.root, range.start_bound(), range.end_bound(), &self.cmp)
+    }
+
+    /// A cursor on the first key at or after `bound`, or on the ghost
+    /// position if there is none.
+    pub fn lower_bound<Q>(&self, bound: Bound<&Q>) -> Cursor<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        Cursor::new(self.root, unsafe { cursor::lower_bound(self.root, bound, &self.cmp) })
+    }
+
+    /// A cursor on the last key at or before `bound`.
+    pub fn upper_bound<Q>(&self, bound: Bound<&Q>) -> Cursor<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        Cursor::new(self.root, unsafe { cursor::upper_bound(self.root, bound, &self.cmp) })
+    }
+
+    pub fn lower_bound_mut<Q>(&mut self, bound: Bound<&Q>) -> CursorMut<'_, T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let current = unsafe { cursor::lower_bound(self.root, bound, &self.cmp) };
+        CursorMut::new(self, current)
+    }
+
+    pub fn upper_bound_mut<Q>(&mut self, bound: Bound<&Q>) -> CursorMut<'_, T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let current = unsafe { cursor::upper_bound(self.root, bound, &self.cmp) };
+        CursorMut::new(self, current)
+    }
+
+    pub fn stats(&self) -> Stats { unsafe { node::stats(self.root) } }
+
+    /// Panics if keys are out of order or colours, parent links or black
+    /// heights are inconsistent.
+    pub fn check_invariants(&self) where T: fmt::Debug {
+        node::check_invariant(self.root, None, None, &self.cmp);
+        assert_eq!(unsafe { node::stats(self.root) }.len, self.len, "Wrong length");
+    }
+
+    /// The tree in Graphviz format.
+    pub fn to_dot(&self) -> String where T: fmt::Display {
+        let mut result = String::from("digraph RBTree {\nnode [shape=circle];\n");
+        if !self.root.is_null() {
+            node::graph_vis(self.root, &mut 0, &mut result);
         }
+        result + "}\n"
+    }
+
+    /// One node with a key, a colour and three pointers per key.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            nodes: self.len,
+            node_size: size_of::<Node<T>>(),
+            total: self.len * size_of::<Node<T>>() + size_of::<Self>(),
+            keys: self.len * size_of::<T>(),
+        }
+    }
+}
+
+
+/// The shape of the tree. Heights count nodes, depths count edges.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub len: usize,
+    pub height: usize,
+    pub black_height: usize,
+    pub total_depth: usize,
+    pub red: usize,
+}
+
+
+impl Stats {
+    pub fn average_depth(&self) -> f64 { self.total_depth as f64 / self.len.max(1) as f64 }
+}
+
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A left-leaning tree is at most twice as high as its black height,
+        // which is at most log2(n + 1).
+        write!(f, "height {} (bound {:.1}), black height {}, average depth {:.2}, {} red nodes ({:.1}%)",
+               self.height, 2.0 * ((self.len + 1) as f64).log2(), self.black_height,
+               self.average_depth(), self.red, 100.0 * self.red as f64 / self.len.max(1) as f64)
     }
 }
 
 
+impl<T, C: Comparator<T>> SortedSet<T> for RBTree<T, C> {
+    type Cmp = C;
+    type Iter<'a> = Range<'a, T> where T: 'a, C: 'a;
+
+    fn len(&self) -> usize { self.len }
+    fn iter(&self) -> Range<'_, T> { RBTree::iter(self) }
+    fn get(&self, key: &T) -> Option<&T> { RBTree::get(self, key) }
+    fn comparator(&self) -> &C { &self.cmp }
+}
+
+
+impl<T, C: Comparator<T> + Default> Default for RBTree<T, C> {
+    fn default() -> Self { RBTree::with_comparator(C::default()) }
+}
+
+
+impl<K: Key> IntSet<K> for RBTree<K> {
+    fn empty() -> Self { RBTree::new() }
+
+    fn get(&self, key: &K) -> bool { self.contains(key) }
+
+    fn put(&mut self, key: K) { self.insert(key); }
+
+    fn len(&self) -> usize { self.len() }
+
+    fn remove(&mut self, key: &K) -> bool { RBTree::remove(self, key) }
+
+    fn scan(&self, from: &K, count: usize) -> i64 {
+        self.range(from..).take(count).fold(0, |acc, key| acc.wrapping_add(key.checksum()))
+    }
+
+    fn memory_usage(&self) -> Option<MemoryUsage> { Some(self.memory_usage()) }
+
+    fn report(&self) {
+        ::profile::report();
+        println!("Shape:  {}\n", self.stats());
+    }
+
+    fn check_invariants(&self) { RBTree::check_invariants(self) }
+}
+
+
+impl<K: Key> NearestKeys<K> for RBTree<K> {
+    fn first(&self) -> Option<&K> { RBTree::first(self) }
+
+    fn last(&self) -> Option<&K> { RBTree::last(self) }
+
+    fn floor(&self, key: &K) -> Option<&K> { RBTree::floor(self, key) }
+
+    fn ceiling(&self, key: &K) -> Option<&K> { RBTree::ceiling(self, key) }
+
+    fn predecessor(&self, key: &K) -> Option<&K> { RBTree::predecessor(self, key) }
+
+    fn successor(&self, key: &K) -> Option<&K> { RBTree::successor(self, key) }
+
+    fn pop_first(&mut self) -> Option<K> { RBTree::pop_first(self) }
+
+    fn pop_last(&mut self) -> Option<K> { RBTree::pop_last(self) }
+}
+
+
 #[test]
 fn rbtree_vs_btreeset() {
     use std::collections::BTreeSet;
-    super::compare_test::<BTreeSet<i64>, RBTree<i64>>();
+    ::test_util::compare_test::<BTreeSet<i64>, RBTree<i64>>();
+}
+
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn rbtree_matches_model(ops in ::test_util::arb_ops()) {
+        use std::collections::BTreeSet;
+        ::test_util::compare_ops::<BTreeSet<i64>, RBTree<i64>>(&ops);
+    }
+
+    #[test]
+    fn rbtree_range_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..200),
+                               start in ::test_util::arb_bound(), end in ::test_util::arb_bound()) {
+        let mut tree = RBTree::new();
+        for &key in &keys {
+            tree.insert(key);
+        }
+        let range: Vec<i64> = tree.range((start, end)).cloned().collect();
+        prop_assert_eq!(range, ::test_util::range_model(&keys, (start, end)));
+    }
+
+    #[test]
+    fn rbtree_split_off_and_append(keys in ::proptest::collection::vec(-500i64..500, 0..600),
+                                   other in ::proptest::collection::vec(-500i64..500, 0..100),
+                                   at in -550i64..550, flip in ::proptest::bool::ANY) {
+        ::test_util::split_off_and_append::<RBTree<i64>>(&keys, &other, at, flip)?;
+    }
+
+    #[test]
+    fn rbtree_nearest_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..200),
+                                   probes in ::proptest::collection::vec(-60i64..60, 0..20),
+                                   pops in ::proptest::collection::vec(::proptest::bool::ANY, 0..250)) {
+        ::test_util::nearest_matches_model::<RBTree<i64>>(&keys, &probes, &pops)?;
+    }
+
+    #[test]
+    fn rbtree_cursor_matches_model(keys in ::proptest::collection::vec(-50i64..50, 0..100),
+                                   bound in ::test_util::arb_bound(),
+                                   upper in ::proptest::bool::ANY,
+                                   ops in ::test_util::arb_cursor_ops()) {
+        ::test_util::cursor_matches_model::<RBTree<i64>>(&keys, bound, upper, ops)?;
+    }
 }
 
+
+#[test]
+fn rbtree_stats() {
+    let mut tree = RBTree::new();
+    assert_eq!(tree.stats(), Stats::default());
+    for i in 0..1023 {
+        tree.insert(i);
+    }
+    let stats = tree.stats();
+    assert_eq!(stats.len, 1023);
+    assert!(stats.black_height <= 10);
+    assert!(stats.height <= 2 * stats.black_height);
+    assert!(stats.average_depth() < stats.height as f64);
+    assert!(stats.red < stats.len / 2);
+}
+
+
+#[test]
+fn rbtree_to_dot() {
+    let mut tree = RBTree::new();
+    assert_eq!(tree.to_dot(), "digraph RBTree {\nnode [shape=circle];\n}\n");
+    for i in 1..4 {
+        tree.insert(i);
+    }
+    let dot = tree.to_dot();
+    assert!(dot.contains("n0 [label=\"2\", color=\"black\"];"));
+    assert!(dot.contains("n1 [label=\"1\", color=\"red\"];"));
+    assert!(dot.contains("n0 -> n1 [color=\"red\"];"));
+    assert!(dot.contains("n0 -> n2 [color=\"red\"];"));
+
+    let mut tree = RBTree::new();
+    tree.insert("a \"b\"".to_string());
+    tree.insert("a \"b\" ".to_string());
+    let dot = tree.to_dot();
+    assert!(dot.contains("n0 [label=\"a \\\"b\\\" \", color=\"black\"];"));
+    assert!(dot.contains("n1 [label=\"a \\\"b\\\"\", color=\"red\"];"));
+}
+
+
+#[test]
+fn rbtree_range() {
+    use std::ops::Bound::Included;
+    let mut tree = RBTree::new();
+    for i in 0..1000 {
+        tree.insert(i * 2);
+    }
+    assert_eq!(tree.len(), 1000);
+    assert!(tree.range(..).cloned().eq((0..1000).map(|i| i * 2)));
+    assert!(tree.range(10..20).cloned().eq(vec![10, 12, 14, 16, 18]));
+    assert!(tree.range(11..=20).cloned().eq(vec![12, 14, 16, 18, 20]));
+    assert!(tree.range(1990..).cloned().eq(vec![1990, 1992, 1994, 1996, 1998]));
+    assert_eq!(tree.range(5000..).next(), None);
+    assert_eq!(tree.range((Included(5000), Included(10))).next(), None);
+}
+
+
+#[test]
+fn rbtree_remove_all() {
+    let mut tree = RBTree::new();
+    for i in 0..1000 {
+        tree.insert((i * 7919) % 1000);
+    }
+    tree.check_invariants();
+    for i in 0..1000 {
+        assert!(tree.remove(&((i * 104_729) % 1000)));
+        assert!(!tree.contains(&((i * 104_729) % 1000)));
+        tree.check_invariants();
+    }
+    assert_eq!(tree.len(), 0);
+    assert_eq!(tree.range(..).next(), None);
+}
+
+
+#[test]
+fn rbtree_custom_comparator() {
+    use compare::Reverse;
+    use std::ops::Bound;
+    let mut tree = RBTree::with_comparator(Reverse(Natural));
+    for i in 0..100 {
+        tree.insert((i * 37) % 100);
+    }
+    tree.check_invariants();
+    assert!(tree.range(..).cloned().eq((0..100).rev()));
+    // In reverse order, 20 comes before 10.
+    let (from, to) = (Bound::Included(20), Bound::Included(10));
+    assert!(tree.range((from, to)).cloned().eq((10..21).rev()));
+    assert!(tree.remove(&50));
+    assert!(!tree.contains(&50));
+
+    let mut tree = RBTree::with_comparator(|a: &String, b: &String| {
+        a.to_lowercase().cmp(&b.to_lowercase())
+    });
+    for word in &["b", "A", "c", "B", "a"] {
+        tree.insert(word.to_string());
+    }
+    tree.check_invariants();
+    assert_eq!(tree.len(), 3);
+    assert!(tree.contains(&"C".to_string()));
+    assert!(tree.range(..).map(String::as_str).eq(vec!["A", "b", "c"]));
+}
+
+
+#[test]
+fn rbtree_borrowed_lookups() {
+    use std::ops::Bound::*;
+    let mut tree = RBTree::new();
+    for word in &["apple", "banana", "cherry", "date"] {
+        tree.insert(word.to_string());
+    }
+    assert!(tree.contains("banana"));
+    assert!(!tree.contains("fig"));
+    assert_eq!(tree.get("cherry").map(String::as_str), Some("cherry"));
+    assert_eq!(tree.get("fig"), None);
+    // `str` is unsized, so its ranges are pairs of bounds.
+    let range = |from, to| tree.range::<str, _>((Included(from), Excluded(to)));
+    assert!(range("b", "d").map(String::as_str).eq(vec!["banana", "cherry"]));
+    assert_eq!(range("d", "b").next(), None);
+    assert!(tree.range::<str, _>((Excluded("banana"), Unbounded)).map(String::as_str)
+            .eq(vec!["cherry", "date"]));
+    assert!(tree.remove("apple"));
+    assert!(!tree.remove("apple"));
+    assert_eq!(tree.len(), 3);
+    tree.check_invariants();
+}
+
+
+#[test]
+fn rbtree_cursor() {
+    ::test_util::cursor_test::<RBTree<i64>>();
+}
+
+
+#[test]
+fn rbtree_shards() {
+    ::test_util::shards_test::<RBTree<i64>>();
+}
+
+
+#[test]
+fn rbtree_split_off_compares_along_a_path() {
+    ::test_util::split_off_compares_along_a_path::<RBTree<i64, ::test_util::Counting>>();
+}
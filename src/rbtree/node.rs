@@ -1,69 +1,129 @@
+use std::borrow::Borrow;
 use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Bound;
 use std::ptr;
 use std::mem::swap;
+use compare::Comparator;
+#[cfg(test)]
+use compare::Natural;
 
 
 #[derive(Debug)]
 pub struct Node<T> {
     pub parent: *mut Node<T>,
     is_red: bool,
-    key: T,
+    pub(super) key: T,
     left: *mut Node<T>,
     right: *mut Node<T>,
 }
 
 
-impl<T: Ord> Node<T> {
+impl<T> Node<T> {
     pub fn new_root(key: T) -> *mut Node<T> {
         Box::into_raw(Box::new(Node {
             is_red: false,
-            key: key,
+            key,
             parent: ptr::null_mut(),
             left: ptr::null_mut(),
             right: ptr::null_mut(),
         }))
     }
 
-    pub fn contains(&self, key: T) -> bool {
-        let child = match key.cmp(&self.key) {
-            Ordering::Equal => return true,
+    pub fn get<Q, C>(&self, key: &Q, cmp: &C) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let child = match cmp.compare(key, self.key.borrow()) {
+            Ordering::Equal => return Some(&self.key),
             Ordering::Less => self.left,
             Ordering::Greater => self.right,
         };
 
         if child.is_null() {
-            return false;
+            return None;
         }
 
-        unsafe { (*child).contains(key) }
+        unsafe { (*child).get(key, cmp) }
     }
 
-    pub fn insert(&mut self, key: T) {
-        let this: *mut Node<T> = self;
-        let child = match key.cmp(&self.key) {
+    pub fn insert<C: Comparator<T>>(&mut self, key: T, cmp: &C) -> bool {
+        let ordering = cmp.compare(&key, &self.key);
+        let child = match ordering {
             Ordering::Equal => {
-                ::tack(unsafe { &mut super::SEARCH_TIME });
-                return
+                ::profile::lap("search");
+                return false
             },
-            Ordering::Less => &mut self.left,
-            Ordering::Greater => &mut self.right,
+            Ordering::Less => self.left,
+            Ordering::Greater => self.right,
         };
 
 
         if child.is_null() {
-            ::tack(unsafe { &mut super::SEARCH_TIME });
-            *child = Node::new_leaf(this, key);
-            unsafe { fixup(*child) };
+            ::profile::lap("search");
+            unsafe { attach(self, ordering == Ordering::Less, key) };
+            ::profile::lap("fixup");
+            true
         } else {
-            unsafe { (**child).insert(key) };
+            unsafe { (*child).insert(key, cmp) }
+        }
+    }
+
+    /// Lower bound search: the first node whose key is not less than `key`
+    /// (or greater than `key`, for `Bound::Excluded`).
+    pub fn seek<Q, C>(&self, start: Bound<&Q>, cmp: &C) -> *const Node<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut result = ptr::null();
+        let mut u: *const Node<T> = self;
+        while !u.is_null() {
+            let go_left = unsafe {
+                match start {
+                    Bound::Unbounded => true,
+                    Bound::Included(key) => !cmp.less((*u).key.borrow(), key),
+                    Bound::Excluded(key) => cmp.less(key, (*u).key.borrow()),
+                }
+            };
+            if go_left {
+                result = u;
+                u = unsafe { (*u).left };
+            } else {
+                u = unsafe { (*u).right };
+            }
         }
+        result
+    }
+
+    /// Upper bound search: the last node whose key is not greater than
+    /// `key` (or less than `key`, for `Bound::Excluded`).
+    pub fn seek_back<Q, C>(&self, end: Bound<&Q>, cmp: &C) -> *const Node<T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut result = ptr::null();
+        let mut u: *const Node<T> = self;
+        while !u.is_null() {
+            let go_right = unsafe {
+                match end {
+                    Bound::Unbounded => true,
+                    Bound::Included(key) => !cmp.less(key, (*u).key.borrow()),
+                    Bound::Excluded(key) => cmp.less((*u).key.borrow(), key),
+                }
+            };
+            if go_right {
+                result = u;
+                u = unsafe { (*u).right };
+            } else {
+                u = unsafe { (*u).left };
+            }
+        }
+        result
     }
 
     fn new_leaf(parent: *mut Node<T>, key: T) -> *mut Node<T> {
         Box::into_raw(Box::new(Node {
             is_red: true,
-            key: key,
-            parent: parent,
+            key,
+            parent,
             left: ptr::null_mut(),
             right: ptr::null_mut(),
         }))
@@ -71,14 +131,53 @@ impl<T: Ord> Node<T> {
 }
 
 
+/// Finds the node with the key equal to `key`, or else the node to hang a
+/// new leaf with the key from, and whether on the left. The parent is null
+/// for an empty tree.
+pub unsafe fn search<T, Q, C>(root: *mut Node<T>, key: &Q, cmp: &C)
+                              -> Result<*mut Node<T>, (*mut Node<T>, bool)>
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let mut parent = ptr::null_mut();
+    let mut left = false;
+    let mut u = root;
+    while !u.is_null() {
+        parent = u;
+        left = match cmp.compare(key, (*u).key.borrow()) {
+            Ordering::Equal => return Ok(u),
+            Ordering::Less => true,
+            Ordering::Greater => false,
+        };
+        u = if left { (*u).left } else { (*u).right };
+    }
+    Err((parent, left))
+}
+
+
+/// Hangs a new leaf with `key` from `parent`, which must have no child on
+/// that side, and rebalances. The new node keeps its key through the
+/// rotations, so the returned pointer stays valid.
+pub unsafe fn attach<T>(parent: *mut Node<T>, left: bool, key: T) -> *mut Node<T> {
+    let leaf = Node::new_leaf(parent, key);
+    if left {
+        (*parent).left = leaf;
+    } else {
+        (*parent).right = leaf;
+    }
+    fixup(leaf);
+    leaf
+}
+
+
 fn is_red<T>(u: *const Node<T>) -> bool {
     !u.is_null() && unsafe { (*u).is_red }
 }
 
 
 unsafe fn fixup<T>(mut u: *mut Node<T>) {
-    ::tick();
+    let mut iterations = 0;
     loop {
+        iterations += 1;
         debug_assert!({ (*u).is_red });
         let mut w = { (*u).parent };
         if w.is_null() {
@@ -88,6 +187,7 @@ unsafe fn fixup<T>(mut u: *mut Node<T>) {
 
         if !is_red((*w).left) {
             flip_left(w);
+            ::profile::tally("fixup flip_left");
             u = w;
             w = (*u).parent;
         }
@@ -101,12 +201,475 @@ unsafe fn fixup<T>(mut u: *mut Node<T>) {
 
         if !is_red((*g).right) {
             flip_right(g);
+            ::profile::tally("fixup flip_right");
             break;
         }
         flip(g);
+        ::profile::tally("fixup flip");
         u = g;
     }
-    ::tack(unsafe { &mut super::FIXUP_TIME });
+    ::profile::count("fixup iterations", iterations);
+}
+
+
+/// Removes `key` from the tree rooted at `root`. Returns the new root and
+/// the removed key, or `None` if there is no such key.
+///
+/// This is the removal from Open Data Structures: the node with the key (or
+/// its successor) is spliced out, and if it was black, its only child `u`
+/// carries an extra black up the tree until it can be absorbed. Instead of
+/// a third colour, the extra black is the `extra` flag of `u`, which may be
+/// null on the first iteration, hence the separately tracked `parent`.
+pub unsafe fn remove<T, Q, C>(root: *mut Node<T>, key: &Q, cmp: &C) -> Option<(*mut Node<T>, T)>
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let mut node = root;
+    loop {
+        if node.is_null() {
+            return None;
+        }
+        node = match cmp.compare(key, (*node).key.borrow()) {
+            Ordering::Equal => break,
+            Ordering::Less => (*node).left,
+            Ordering::Greater => (*node).right,
+        };
+    }
+    let (root, removed, _) = remove_node(node);
+    Some((root, removed))
+}
+
+
+/// Removes `node` from its tree, returning the new root, the key and the
+/// node that now holds the next key, if any. If `node` has a right child,
+/// the key of its successor moves into `node`, which stays in the tree;
+/// otherwise `node` itself is freed.
+pub unsafe fn remove_node<T>(node: *mut Node<T>) -> (*mut Node<T>, T, *mut Node<T>) {
+    let mut w = (*node).right;
+    let mut next = node;
+    let u = if w.is_null() {
+        next = self::next(node) as *mut Node<T>;
+        w = node;
+        (*w).left
+    } else {
+        while !(*w).left.is_null() {
+            w = (*w).left;
+        }
+        swap(&mut (*node).key, &mut (*w).key);
+        (*w).right
+    };
+
+    let parent = (*w).parent;
+    if !u.is_null() {
+        (*u).parent = parent;
+    }
+    if !parent.is_null() {
+        if ptr::eq((*parent).left, w) {
+            (*parent).left = u;
+        } else {
+            (*parent).right = u;
+        }
+    }
+    let extra = !(*w).is_red && !is_red(u);
+    if !u.is_null() {
+        (*u).is_red = false;
+    }
+    let removed = Box::from_raw(w).key;
+
+    (remove_fixup(u, parent, extra), removed, next)
+}
+
+
+unsafe fn remove_fixup<T>(mut u: *mut Node<T>, mut parent: *mut Node<T>, mut extra: bool)
+                          -> *mut Node<T> {
+    while extra && !parent.is_null() {
+        let w = parent;
+        if is_red((*w).left) {
+            flip_right(w);
+            continue;
+        }
+
+        let w_was_red = (*w).is_red;
+        if ptr::eq((*w).left, u) {
+            let v = (*w).right;
+            (*v).is_red = true;
+            (*w).is_red = false;
+            flip_left(w);
+            let q = (*w).right;
+            if is_red(q) {
+                rotate_left(w);
+                flip_right(v);
+                (*q).is_red = w_was_red;
+                (*w).is_red = false;
+                (*v).is_red = false;
+                if is_red((*v).right) {
+                    flip_left(v);
+                }
+                u = q;
+                extra = false;
+            } else {
+                u = v;
+                extra = !w_was_red;
+            }
+        } else {
+            let v = (*w).left;
+            (*v).is_red = true;
+            (*w).is_red = false;
+            flip_right(w);
+            let q = (*w).left;
+            if is_red(q) {
+                rotate_right(w);
+                flip_left(v);
+                (*q).is_red = w_was_red;
+                (*w).is_red = false;
+                (*v).is_red = false;
+                u = q;
+                extra = false;
+            } else if is_red((*v).left) {
+                (*v).is_red = w_was_red;
+                (*(*v).left).is_red = false;
+                (*w).is_red = false;
+                u = v;
+                extra = false;
+            } else {
+                flip_left(v);
+                u = w;
+                extra = !w_was_red;
+            }
+        }
+        parent = (*u).parent;
+    }
+
+    if !parent.is_null() && is_red((*parent).right) && !is_red((*parent).left) {
+        flip_left(parent);
+    }
+
+    let mut root = if parent.is_null() { u } else { parent };
+    while !root.is_null() && !(*root).parent.is_null() {
+        root = (*root).parent;
+    }
+    if !root.is_null() {
+        (*root).is_red = false;
+    }
+    root
+}
+
+
+/// Plain rotation, without the colour swap of `flip_left`.
+unsafe fn rotate_left<T>(u: *mut Node<T>) {
+    swap_colors(u, (*u).right);
+    flip_left(u);
+}
+
+
+/// Plain rotation, without the colour swap of `flip_right`.
+unsafe fn rotate_right<T>(u: *mut Node<T>) {
+    swap_colors(u, (*u).left);
+    flip_right(u);
+}
+
+
+/// Frees all nodes of the tree, without recursion.
+pub unsafe fn destroy<T>(root: *mut Node<T>) {
+    let mut u = root;
+    while !u.is_null() {
+        if !(*u).left.is_null() {
+            u = (*u).left;
+        } else if !(*u).right.is_null() {
+            u = (*u).right;
+        } else {
+            let parent = (*u).parent;
+            if !parent.is_null() {
+                if ptr::eq((*parent).left, u) {
+                    (*parent).left = ptr::null_mut();
+                } else {
+                    (*parent).right = ptr::null_mut();
+                }
+            }
+            drop(Box::from_raw(u));
+            u = parent;
+        }
+    }
+}
+
+
+/// Walks the whole tree, without recursion.
+pub unsafe fn stats<T>(root: *const Node<T>) -> super::Stats {
+    let mut stats = super::Stats { black_height: black_height(root), ..Default::default() };
+    let mut stack = vec![(root, 1)];
+    while let Some((u, depth)) = stack.pop() {
+        if u.is_null() {
+            continue;
+        }
+        stats.len += 1;
+        stats.height = stats.height.max(depth);
+        stats.total_depth += depth - 1;
+        stats.red += is_red(u) as usize;
+        stack.push(((*u).left, depth + 1));
+        stack.push(((*u).right, depth + 1));
+    }
+    stats
+}
+
+
+/// The number of nodes under `u`, recursing only into left children and so
+/// only as deep as the tree is high.
+pub unsafe fn count<T>(mut u: *const Node<T>) -> usize {
+    let mut n = 0;
+    while !u.is_null() {
+        n += 1 + count((*u).left);
+        u = (*u).right;
+    }
+    n
+}
+
+
+/// Splits the tree rooted at `u` into the keys before `key` and the rest,
+/// returning both roots. Each node on the search path joins the pieces on
+/// its side with its own subtree, from the bottom up, and the joins take
+/// time proportional to the differences in black height, which add up to
+/// the height of the tree.
+pub unsafe fn split<T, Q, C>(u: *mut Node<T>, key: &Q, cmp: &C) -> (*mut Node<T>, *mut Node<T>)
+    where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    if u.is_null() {
+        return (ptr::null_mut(), ptr::null_mut());
+    }
+    let (left, right) = ((*u).left, (*u).right);
+    detach(left);
+    detach(right);
+    let (lower, upper) = match cmp.compare(key, (*u).key.borrow()) {
+        Ordering::Less => {
+            let (lower, upper) = split(left, key, cmp);
+            (lower, join(upper, u, right))
+        }
+        Ordering::Greater => {
+            let (lower, upper) = split(right, key, cmp);
+            (join(left, u, lower), upper)
+        }
+        Ordering::Equal => (left, join(ptr::null_mut(), u, right)),
+    };
+    // A subtree cut loose may have a red root.
+    make_black(lower);
+    make_black(upper);
+    (lower, upper)
+}
+
+
+/// Joins the trees rooted at `left` and `right` with the node `x` between
+/// them, all in order, returning the new root. Either tree may be empty.
+///
+/// The tree with the smaller black height hangs from the edge of the other
+/// one, with `x` as a red node on top, in place of the subtree of the same
+/// black height. That leaves the tree as after inserting a red node, so the
+/// insertion fixup rebalances it.
+pub unsafe fn join<T>(left: *mut Node<T>, x: *mut Node<T>, right: *mut Node<T>) -> *mut Node<T> {
+    make_black(left);
+    make_black(right);
+    let (lheight, rheight) = (black_height(left), black_height(right));
+    (*x).parent = ptr::null_mut();
+    if lheight == rheight {
+        (*x).is_red = false;
+        set_children(x, left, right);
+        return x;
+    }
+
+    // Walk down the edge of the higher tree to the first black node, or
+    // null, with the black height of the other tree.
+    let on_left = lheight < rheight;
+    let (mut u, mut height, target) = if on_left {
+        (right, rheight, lheight)
+    } else {
+        (left, lheight, rheight)
+    };
+    let mut parent = ptr::null_mut();
+    while is_red(u) || height > target {
+        height -= !is_red(u) as usize;
+        parent = u;
+        u = if on_left { (*u).left } else { (*u).right };
+    }
+
+    (*x).is_red = true;
+    (*x).parent = parent;
+    if on_left {
+        set_children(x, left, u);
+        (*parent).left = x;
+    } else {
+        set_children(x, u, right);
+        (*parent).right = x;
+    }
+    fixup(x);
+    root_of(x)
+}
+
+
+unsafe fn set_children<T>(u: *mut Node<T>, left: *mut Node<T>, right: *mut Node<T>) {
+    (*u).left = left;
+    (*u).right = right;
+    for &child in &[left, right] {
+        if !child.is_null() {
+            (*child).parent = u;
+        }
+    }
+}
+
+
+/// The number of black nodes on every path from `u` down to a leaf.
+pub unsafe fn black_height<T>(mut u: *const Node<T>) -> usize {
+    let mut height = 0;
+    while !u.is_null() {
+        height += !(*u).is_red as usize;
+        u = (*u).left;
+    }
+    height
+}
+
+
+unsafe fn make_black<T>(u: *mut Node<T>) {
+    if !u.is_null() {
+        (*u).is_red = false;
+    }
+}
+
+
+unsafe fn detach<T>(u: *mut Node<T>) {
+    if !u.is_null() {
+        (*u).parent = ptr::null_mut();
+    }
+}
+
+
+/// Hangs a new leaf with `key` right after `u` in order: as the right child
+/// of `u`, or as the left child of the first node of its right subtree.
+pub unsafe fn attach_after<T>(u: *mut Node<T>, key: T) -> *mut Node<T> {
+    if (*u).right.is_null() {
+        attach(u, false, key)
+    } else {
+        attach(first((*u).right), true, key)
+    }
+}
+
+
+/// Hangs a new leaf with `key` right before `u` in order.
+pub unsafe fn attach_before<T>(u: *mut Node<T>, key: T) -> *mut Node<T> {
+    if (*u).left.is_null() {
+        attach(u, true, key)
+    } else {
+        attach(last((*u).left), false, key)
+    }
+}
+
+
+/// The leftmost node of the non-empty subtree `u`.
+pub unsafe fn first<T>(mut u: *mut Node<T>) -> *mut Node<T> {
+    while !(*u).left.is_null() {
+        u = (*u).left;
+    }
+    u
+}
+
+
+/// The rightmost node of the non-empty subtree `u`.
+pub unsafe fn last<T>(mut u: *mut Node<T>) -> *mut Node<T> {
+    while !(*u).right.is_null() {
+        u = (*u).right;
+    }
+    u
+}
+
+
+/// The root of the tree containing `u`.
+pub unsafe fn root_of<T>(mut u: *mut Node<T>) -> *mut Node<T> {
+    while !(*u).parent.is_null() {
+        u = (*u).parent;
+    }
+    u
+}
+
+
+/// In-order predecessor, following parent links.
+pub unsafe fn prev<T>(mut u: *const Node<T>) -> *const Node<T> {
+    if !(*u).left.is_null() {
+        u = (*u).left;
+        while !(*u).right.is_null() {
+            u = (*u).right;
+        }
+        return u;
+    }
+    loop {
+        let parent = (*u).parent;
+        if parent.is_null() || ptr::eq((*parent).right, u) {
+            return parent;
+        }
+        u = parent;
+    }
+}
+
+
+/// In-order successor, following parent links.
+pub unsafe fn next<T>(mut u: *const Node<T>) -> *const Node<T> {
+    if !(*u).right.is_null() {
+        u = (*u).right;
+        while !(*u).left.is_null() {
+            u = (*u).left;
+        }
+        return u;
+    }
+    loop {
+        let parent = (*u).parent;
+        if parent.is_null() || ptr::eq((*parent).left, u) {
+            return parent;
+        }
+        u = parent;
+    }
+}
+
+
+/// In-order iterator over the nodes from `current` up to, but excluding, `end`.
+pub struct Range<'a, T: 'a> {
+    current: *const Node<T>,
+    end: *const Node<T>,
+    marker: PhantomData<&'a Node<T>>,
+}
+
+
+impl<'a, T> Range<'a, T> {
+    pub(super) fn new<Q, C>(root: *const Node<T>, start: Bound<&Q>, end: Bound<&Q>, cmp: &C)
+                            -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        if root.is_null() {
+            return Range { current: ptr::null(), end: ptr::null(), marker: PhantomData };
+        }
+        let (mut current, end) = unsafe {
+            let end = match end {
+                Bound::Unbounded => ptr::null(),
+                Bound::Included(key) => (*root).seek(Bound::Excluded(key), cmp),
+                Bound::Excluded(key) => (*root).seek(Bound::Included(key), cmp),
+            };
+            ((*root).seek(start, cmp), end)
+        };
+        // An empty range, including one whose start is past all keys but
+        // whose end is not.
+        if current.is_null() || !end.is_null()
+            && unsafe { !cmp.less((*current).key.borrow(), (*end).key.borrow()) } {
+            current = end;
+        }
+        Range { current, end, marker: PhantomData }
+    }
+}
+
+
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if ptr::eq(self.current, self.end) {
+            return None;
+        }
+        let key = unsafe { &(*self.current).key };
+        self.current = unsafe { next(self.current) };
+        Some(key)
+    }
 }
 
 
@@ -152,42 +715,58 @@ unsafe fn swap_child<T>(old: *mut Node<T>, new: *mut Node<T>) {
     let parent = (*old).parent;
     (*new).parent = parent;
     if parent.is_null() { return; }
-    let slot = if (*parent).left == old {
+    let slot = if ptr::eq((*parent).left, old) {
         &mut (*parent).left
     } else {
-        debug_assert!((*parent).right == old);
+        debug_assert!(ptr::eq((*parent).right, old));
         &mut (*parent).right
     };
     *slot = new;
 }
 
 
-#[cfg(test)]
-#[allow(unused)]
-fn graph_vis<T: ::std::fmt::Display>(u: *const Node<T>) -> String {
+/// DOT statements for the subtree. The edge to a red node is drawn red, as
+/// it glues the node to its parent. Nodes are numbered in preorder, so that
+/// equal-looking keys do not merge. Returns the node's name.
+pub fn graph_vis<T: fmt::Display>(u: *const Node<T>, next_id: &mut usize,
+                                  out: &mut String) -> String {
+    let name = format!("n{}", *next_id);
+    *next_id += 1;
     unsafe {
-        let mut result = format!("{} [color=\"{}\"];\n",
-                                 (*u).key,
-                                 if (*u).is_red { "red" } else { "black" });
+        *out += &format!("{} [label=\"{}\", color=\"{}\"];\n",
+                         name,
+                         ::dot_label(&(*u).key.to_string(), false),
+                         if (*u).is_red { "red" } else { "black" });
 
         for &child in &[(*u).left, (*u).right] {
             if !child.is_null() {
-                result += &format!("{} -> {};\n", (*u).key, (*child).key);
-                result += &format!("{} -> {};\n", (*child).key, (*(*child).parent).key);
-                result += &graph_vis(child);
+                let child_name = graph_vis(child, next_id, out);
+                *out += &format!("{} -> {} [color=\"{}\"];\n", name, child_name,
+                                 if (*child).is_red { "red" } else { "black" });
             }
         }
-        result
     }
+    name
 }
 
 
-#[cfg(test)]
-fn check_invariant<T>(u: *const Node<T>) -> usize {
+/// Checks the subtree of `u`, whose keys must lie strictly between `lower`
+/// and `upper`, and returns its black height counting the null leaves.
+pub fn check_invariant<T, C>(u: *const Node<T>, lower: Option<&T>, upper: Option<&T>,
+                             cmp: &C) -> usize
+    where T: fmt::Debug, C: Comparator<T>
+{
     unsafe {
         if u.is_null() {
             return 1
         }
+        let key = &(*u).key;
+        if let Some(lower) = lower {
+            assert!(cmp.less(lower, key), "Key {:?} not above {:?}", key, lower);
+        }
+        if let Some(upper) = upper {
+            assert!(cmp.less(key, upper), "Key {:?} not below {:?}", key, upper);
+        }
         let parent = (*u).parent;
         if parent.is_null() {
             assert!(!is_red(u), "Root is not black");
@@ -197,9 +776,9 @@ fn check_invariant<T>(u: *const Node<T>) -> usize {
         let right = (*u).right;
 
         assert!(left.is_null() || left != right, "Duplicate child");
-        assert!(left.is_null() || (*left).parent as *const Node<T> == u,
+        assert!(left.is_null() || ptr::eq((*left).parent, u),
         "Wrong parent link on the left");
-        assert!(right.is_null() || (*right).parent as *const Node<T> == u,
+        assert!(right.is_null() || ptr::eq((*right).parent, u),
         "Wrong parent link on the right");
 
         if is_red(right) {
@@ -210,10 +789,10 @@ fn check_invariant<T>(u: *const Node<T>) -> usize {
             assert!(!is_red(left) && !(is_red(right)), "Two consecutive red edges");
         }
 
-        let left_path = check_invariant(left);
-        let right_path = check_invariant(right);
+        let left_path = check_invariant(left, lower, Some(key), cmp);
+        let right_path = check_invariant(right, Some(key), upper, cmp);
         assert_eq!(left_path, right_path, "Different number of black nodes");
-        return left_path + if is_red(u) { 0 } else { 1 }
+        left_path + if is_red(u) { 0 } else { 1 }
     }
 }
 
@@ -221,7 +800,7 @@ fn check_invariant<T>(u: *const Node<T>) -> usize {
 #[test]
 fn test_create_root() {
     let n = Node::new_root(92);
-    check_invariant(n);
+    check_invariant(n, None, None, &Natural);
     unsafe {
         assert_eq!((*n).key, 92);
     }
@@ -232,12 +811,12 @@ fn test_create_root() {
 fn test_insert() {
     let n = Node::new_root(1);
     unsafe {
-        (*n).insert(0);
+        (*n).insert(0, &Natural);
         assert_eq!((*(*n).left).key, 0);
-        check_invariant(n);
-        (*n).insert(2);
+        check_invariant(n, None, None, &Natural);
+        (*n).insert(2, &Natural);
         assert_eq!( (*(*n).right).key, 2);
-        check_invariant(n);
+        check_invariant(n, None, None, &Natural);
     }
 }
 
@@ -246,8 +825,8 @@ fn test_insert() {
 fn test_insert_same() {
     let n = Node::new_root(92);
     unsafe {
-        (*n).insert(92);
-        check_invariant(n);
+        (*n).insert(92, &Natural);
+        check_invariant(n, None, None, &Natural);
         assert_eq!((*n).left, ptr::null_mut());
         assert_eq!((*n).right, ptr::null_mut());
     }
@@ -258,23 +837,23 @@ fn test_insert_same() {
 fn test_insert_left() {
     let n = Node::new_root(5);
     unsafe {
-        (*n).insert(4);
+        (*n).insert(4, &Natural);
         let left = &mut *(*n).left;
         assert_eq!(left.key, 4);
-        check_invariant(n);
-        (*n).insert(3);
+        check_invariant(n, None, None, &Natural);
+        (*n).insert(3, &Natural);
         let left_left = &*left.left;
         assert_eq!(left.key, 4);
         assert_eq!(left_left.key, 3);
-        check_invariant(n);
+        check_invariant(n, None, None, &Natural);
         assert!(left.parent.is_null());
         assert!((*n).parent == left);
         assert!((*left.left).is_red);
         assert!((*left.right).is_red);
 
-        left.insert(2);
+        left.insert(2, &Natural);
         assert!(left.parent.is_null());
-        check_invariant(left);
+        check_invariant(left, None, None, &Natural);
     }
 }
 
@@ -283,19 +862,19 @@ fn test_insert_left() {
 fn test_insert_right() {
     let n = Node::new_root(5);
     unsafe {
-        (*n).insert(6);
+        (*n).insert(6, &Natural);
         let root = &mut *(*n).parent;
         assert_eq!((*n).key, 5);
         assert_eq!(root.key, 6);
         assert!(root.parent.is_null());
-        check_invariant(root);
-        root.insert(7);
+        check_invariant(root, None, None, &Natural);
+        root.insert(7, &Natural);
         assert!(root.parent.is_null());
-        check_invariant(root);
+        check_invariant(root, None, None, &Natural);
 
-        root.insert(8);
+        root.insert(8, &Natural);
         assert!(root.parent.is_null());
-        check_invariant(root);
+        check_invariant(root, None, None, &Natural);
         assert_eq!(root.key, 6);
         assert_eq!((*root.left).key, 5);
         assert_eq!((*root.right).key, 8);
@@ -309,15 +888,15 @@ fn check_inserts(keys: &[u32]) {
     unsafe {
         let mut root = Node::new_root(keys[0]);
         for &k in &keys[1..] {
-            (*root).insert(k);
+            (*root).insert(k, &Natural);
             if !(*root).parent.is_null() {
                 root = (*root).parent;
             }
             assert!((*root).parent.is_null());
-            check_invariant(root);
+            check_invariant(root, None, None, &Natural);
         }
 
-        Box::from_raw(root);
+        destroy(root);
     }
 }
 
@@ -330,11 +909,68 @@ fn many_inserts() {
 
 #[test]
 fn random_inserts() {
-    use rand;
-    let n = rand::random::<usize>() % 100;
+    use rand::Rng;
+    let mut rng = ::rng::TestRng::new();
+    let n = rng.gen::<usize>() % 100 + 1;
     let mut elements = vec![];
     for _ in 0..n {
-        elements.push(rand::random::<u32>() % 100)
+        elements.push(rng.gen::<u32>() % 100)
     }
     check_inserts(&elements);
 }
+
+
+#[cfg(test)]
+fn check_removes(inserts: &[u32], removes: &[u32]) {
+    unsafe {
+        let mut root = Node::new_root(inserts[0]);
+        for &k in &inserts[1..] {
+            (*root).insert(k, &Natural);
+            if !(*root).parent.is_null() {
+                root = (*root).parent;
+            }
+        }
+        for &k in removes {
+            if root.is_null() {
+                break;
+            }
+            if let Some((new_root, _)) = remove(root, &k, &Natural) {
+                root = new_root;
+                check_invariant(root, None, None, &Natural);
+            }
+        }
+        destroy(root);
+    }
+}
+
+
+#[test]
+fn random_removes() {
+    use rand::Rng;
+    let mut rng = ::rng::TestRng::new();
+    for _ in 0..100 {
+        let n = rng.gen::<usize>() % 100 + 1;
+        let mut inserts = vec![];
+        let mut removes = vec![];
+        for _ in 0..n {
+            inserts.push(rng.gen::<u32>() % 100);
+            removes.push(rng.gen::<u32>() % 100);
+        }
+        check_removes(&inserts, &removes);
+    }
+}
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn prop_inserts(keys in ::proptest::collection::vec(0..100u32, 1..100)) {
+        check_inserts(&keys);
+    }
+
+    #[test]
+    fn prop_removes(inserts in ::proptest::collection::vec(0..100u32, 1..100),
+                    removes in ::proptest::collection::vec(0..100u32, 0..100)) {
+        check_removes(&inserts, &removes);
+    }
+}
@@ -0,0 +1,483 @@
+//! A persistent `RBTree`. Versions share their nodes through `Arc`, and an
+//! update copies only the nodes it recolours or relinks, which are on the
+//! path from the root to the key it changes.
+//!
+//! This is a separate implementation of Sedgewick's left-leaning red-black
+//! trees and shares no code with `RBTree`, whose bottom-up fixups follow
+//! parent links that immutable shared nodes cannot have. Updates go down
+//! recursively and rebalance on the way back up with their own rotations
+//! and colour flips. The trees satisfy the same invariant as `RBTree`'s,
+//! but colours are flipped as soon as a node has two red children, so
+//! every black node has at most one red child.
+
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem::{replace, size_of, swap};
+use std::ops::{Bound, RangeBounds};
+use std::ptr;
+use std::sync::Arc;
+use super::super::IntSet;
+use compare::{Comparator, Natural};
+use key::Key;
+use memory::MemoryUsage;
+
+
+type Link<T> = Option<Arc<Node<T>>>;
+
+
+#[derive(Clone, Debug)]
+struct Node<T> {
+    key: T,
+    is_red: bool,
+    left: Link<T>,
+    right: Link<T>,
+}
+
+
+/// An immutable set ordered by the comparator `C`. `insert` and `remove`
+/// return a new version and leave this one as it was; `clone` is a
+/// snapshot that takes constant time.
+pub struct PersistentRBTree<T, C = Natural> {
+    root: Link<T>,
+    len: usize,
+    cmp: C,
+}
+
+
+impl<T: Clone + Ord> PersistentRBTree<T> {
+    pub fn new() -> PersistentRBTree<T> { PersistentRBTree::with_comparator(Natural) }
+}
+
+
+impl<T: Clone, C: Comparator<T> + Clone> PersistentRBTree<T, C> {
+    pub fn with_comparator(cmp: C) -> PersistentRBTree<T, C> {
+        PersistentRBTree { root: None, len: 0, cmp }
+    }
+
+    pub fn comparator(&self) -> &C { &self.cmp }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// The key in the set that compares equal to `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut link = &self.root;
+        while let Some(ref u) = *link {
+            link = match self.cmp.compare(key, u.key.borrow()) {
+                Ordering::Equal => return Some(&u.key),
+                Ordering::Less => &u.left,
+                Ordering::Greater => &u.right,
+            };
+        }
+        None
+    }
+
+    /// The version with `key` added. If `key` is already in the set, that
+    /// is this version again.
+    pub fn insert(&self, key: T) -> PersistentRBTree<T, C> {
+        let mut version = self.clone();
+        if self.contains(&key) {
+            return version;
+        }
+        insert(&mut version.root, key, &self.cmp);
+        make_black(version.root.as_mut().unwrap());
+        version.len += 1;
+        version
+    }
+
+    /// The version without `key`.
+    pub fn remove<Q>(&self, key: &Q) -> PersistentRBTree<T, C>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut version = self.clone();
+        if !self.contains(key) {
+            return version;
+        }
+        {
+            // Going down from a red root keeps the invariant of `remove`
+            // that the current node or its left child is red.
+            let root = version.root.as_mut().unwrap();
+            if !is_red(&root.left) && !is_red(&root.right) {
+                Arc::make_mut(root).is_red = true;
+            }
+        }
+        remove(&mut version.root, key, &self.cmp);
+        if let Some(ref mut root) = version.root {
+            make_black(root);
+        }
+        version.len -= 1;
+        version
+    }
+
+    /// The keys in order.
+    pub fn iter(&self) -> Range<'_, T> { self.range::<T, _>(..) }
+
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>
+    {
+        Range::new(&self.root, range.start_bound(), range.end_bound(), &self.cmp)
+    }
+
+    /// Panics if the order, the colours or the black heights are off.
+    pub fn check_invariants(&self) where T: fmt::Debug {
+        assert!(!is_red(&self.root), "Root is not black");
+        let mut count = 0;
+        check_invariant(&self.root, None, None, &mut count, &self.cmp);
+        assert_eq!(count, self.len, "Wrong length");
+    }
+
+    /// One node per key, with its reference counts.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let node_size = size_of::<Node<T>>() + 2 * size_of::<usize>();
+        MemoryUsage {
+            nodes: self.len,
+            node_size,
+            total: self.len * node_size + size_of::<Self>(),
+            keys: self.len * size_of::<T>(),
+        }
+    }
+}
+
+
+impl<T, C: Clone> Clone for PersistentRBTree<T, C> {
+    fn clone(&self) -> Self {
+        PersistentRBTree { root: self.root.clone(), len: self.len, cmp: self.cmp.clone() }
+    }
+}
+
+
+impl<T: Clone, C: Comparator<T> + Clone + Default> Default for PersistentRBTree<T, C> {
+    fn default() -> Self { PersistentRBTree::with_comparator(C::default()) }
+}
+
+
+impl<T: fmt::Debug, C> fmt::Debug for PersistentRBTree<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PersistentRBTree").field("root", &self.root).field("len", &self.len).finish()
+    }
+}
+
+
+fn is_red<T>(u: &Link<T>) -> bool { u.as_ref().is_some_and(|u| u.is_red) }
+
+
+fn left_is_red<T>(u: &Link<T>) -> bool { u.as_ref().is_some_and(|u| is_red(&u.left)) }
+
+
+fn make_black<T: Clone>(u: &mut Arc<Node<T>>) {
+    if u.is_red {
+        Arc::make_mut(u).is_red = false;
+    }
+}
+
+
+/// Inserts a key that is not in the tree yet.
+fn insert<T: Clone, C: Comparator<T>>(link: &mut Link<T>, key: T, cmp: &C) {
+    let u = match *link {
+        Some(ref mut u) => u,
+        None => {
+            *link = Some(Arc::new(Node { key, is_red: true, left: None, right: None }));
+            return;
+        }
+    };
+    let node = Arc::make_mut(u);
+    if cmp.less(&key, &node.key) {
+        insert(&mut node.left, key, cmp);
+    } else {
+        insert(&mut node.right, key, cmp);
+    }
+    balance(u);
+}
+
+
+/// Removes a key that is in the tree. On the way down, the current node or
+/// its left child is red, so that the key is never removed from a 2-node.
+fn remove<T, Q, C>(link: &mut Link<T>, key: &Q, cmp: &C) -> T
+    where T: Clone + Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+{
+    let u = link.as_mut().unwrap();
+    let removed = if cmp.less(key, u.key.borrow()) {
+        if !is_red(&u.left) && !left_is_red(&u.left) {
+            move_red_left(u);
+        }
+        remove(&mut Arc::make_mut(u).left, key, cmp)
+    } else {
+        if is_red(&u.left) {
+            flip_right(u);
+        }
+        let found = cmp.compare(key, u.key.borrow()) == Ordering::Equal;
+        if found && u.right.is_none() {
+            return into_key(link.take().unwrap());
+        }
+        if !is_red(&u.right) && !left_is_red(&u.right) {
+            move_red_right(u);
+        }
+        let node = Arc::make_mut(u);
+        if cmp.compare(key, node.key.borrow()) == Ordering::Equal {
+            let successor = remove_first(&mut node.right);
+            replace(&mut node.key, successor)
+        } else {
+            remove(&mut node.right, key, cmp)
+        }
+    };
+    balance(u);
+    removed
+}
+
+
+fn remove_first<T: Clone>(link: &mut Link<T>) -> T {
+    let u = link.as_mut().unwrap();
+    if u.left.is_none() {
+        return into_key(link.take().unwrap());
+    }
+    if !is_red(&u.left) && !left_is_red(&u.left) {
+        move_red_left(u);
+    }
+    let first = remove_first(&mut Arc::make_mut(u).left);
+    balance(u);
+    first
+}
+
+
+/// The key of a node that left the tree, which other versions may still
+/// hold.
+fn into_key<T: Clone>(u: Arc<Node<T>>) -> T {
+    Arc::try_unwrap(u).map(|u| u.key).unwrap_or_else(|u| u.key.clone())
+}
+
+
+/// Restores the left-leaning invariant below `u` on the way up.
+fn balance<T: Clone>(u: &mut Arc<Node<T>>) {
+    if is_red(&u.right) && !is_red(&u.left) {
+        flip_left(u);
+    }
+    if is_red(&u.left) && left_is_red(&u.left) {
+        flip_right(u);
+    }
+    if is_red(&u.left) && is_red(&u.right) {
+        flip(u);
+    }
+}
+
+
+/// Makes the left child of `u` or one of its children red, borrowing from
+/// the right sibling if it can.
+fn move_red_left<T: Clone>(u: &mut Arc<Node<T>>) {
+    flip(u);
+    if left_is_red(&u.right) {
+        flip_right(Arc::make_mut(u).right.as_mut().unwrap());
+        flip_left(u);
+        flip(u);
+    }
+}
+
+
+fn move_red_right<T: Clone>(u: &mut Arc<Node<T>>) {
+    flip(u);
+    if left_is_red(&u.left) {
+        flip_right(u);
+        flip(u);
+    }
+}
+
+
+fn flip<T: Clone>(u: &mut Arc<Node<T>>) {
+    let node = Arc::make_mut(u);
+    node.is_red = !node.is_red;
+    for child in [&mut node.left, &mut node.right] {
+        let child = Arc::make_mut(child.as_mut().unwrap());
+        child.is_red = !child.is_red;
+    }
+}
+
+
+/// Rotates the right child of `u` into its place, swapping their colours.
+fn flip_left<T: Clone>(u: &mut Arc<Node<T>>) {
+    let mut right = Arc::make_mut(u).right.take().unwrap();
+    {
+        let (node, right) = (Arc::make_mut(u), Arc::make_mut(&mut right));
+        swap(&mut node.is_red, &mut right.is_red);
+        node.right = right.left.take();
+    }
+    let node = replace(u, right);
+    Arc::make_mut(u).left = Some(node);
+}
+
+
+/// Rotates the left child of `u` into its place, swapping their colours.
+fn flip_right<T: Clone>(u: &mut Arc<Node<T>>) {
+    let mut left = Arc::make_mut(u).left.take().unwrap();
+    {
+        let (node, left) = (Arc::make_mut(u), Arc::make_mut(&mut left));
+        swap(&mut node.is_red, &mut left.is_red);
+        node.left = left.right.take();
+    }
+    let node = replace(u, left);
+    Arc::make_mut(u).right = Some(node);
+}
+
+
+/// The black height of the subtree, counting the missing leaves.
+fn check_invariant<T, C>(link: &Link<T>, lower: Option<&T>, upper: Option<&T>, count: &mut usize,
+                         cmp: &C) -> usize
+    where T: fmt::Debug, C: Comparator<T>
+{
+    let u = match *link {
+        None => return 1,
+        Some(ref u) => u,
+    };
+    *count += 1;
+    if let Some(lower) = lower {
+        assert!(cmp.less(lower, &u.key), "Key {:?} not above {:?}", u.key, lower);
+    }
+    if let Some(upper) = upper {
+        assert!(cmp.less(&u.key, upper), "Key {:?} not below {:?}", u.key, upper);
+    }
+    if is_red(&u.right) {
+        assert!(is_red(&u.left), "Not left leaning");
+    }
+    if u.is_red {
+        assert!(!is_red(&u.left) && !is_red(&u.right), "Two consecutive red edges");
+    }
+    let left = check_invariant(&u.left, lower, Some(&u.key), count, cmp);
+    let right = check_invariant(&u.right, Some(&u.key), upper, count, cmp);
+    assert_eq!(left, right, "Different number of black nodes");
+    left + !u.is_red as usize
+}
+
+
+/// An iterator over a range of keys of one version.
+pub struct Range<'a, T: 'a> {
+    /// The nodes whose keys are still to come and whose right subtrees
+    /// are still to be visited, the next one on top.
+    stack: Vec<&'a Node<T>>,
+    /// The first key after the range, or null.
+    end: *const T,
+}
+
+
+impl<'a, T> Range<'a, T> {
+    fn new<Q, C>(root: &'a Link<T>, start: Bound<&Q>, end: Bound<&Q>, cmp: &C) -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let end = match end {
+            Bound::Unbounded => None,
+            Bound::Included(key) => Range::seek(root, Bound::Excluded(key), cmp).peek(),
+            Bound::Excluded(key) => Range::seek(root, Bound::Included(key), cmp).peek(),
+        };
+        let mut range = Range::seek(root, start, cmp);
+        range.end = end.map_or(ptr::null(), |end| end as *const T);
+        // A range whose start is past its end is empty.
+        if let (Some(first), Some(end)) = (range.peek(), end) {
+            if !cmp.less(first.borrow(), end.borrow()) {
+                range.stack.clear();
+            }
+        }
+        range
+    }
+
+    /// The range from the first key at or after `start` to the end.
+    fn seek<Q, C>(root: &'a Link<T>, start: Bound<&Q>, cmp: &C) -> Range<'a, T>
+        where T: Borrow<Q>, Q: ?Sized, C: Comparator<Q>
+    {
+        let mut stack = Vec::new();
+        let mut link = root;
+        while let Some(ref u) = *link {
+            let go_left = match start {
+                Bound::Unbounded => true,
+                Bound::Included(key) => !cmp.less(u.key.borrow(), key),
+                Bound::Excluded(key) => cmp.less(key, u.key.borrow()),
+            };
+            if go_left {
+                stack.push(&**u);
+                link = &u.left;
+            } else {
+                link = &u.right;
+            }
+        }
+        Range { stack, end: ptr::null() }
+    }
+
+    fn peek(&self) -> Option<&'a T> { self.stack.last().map(|u| &u.key) }
+}
+
+
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let u = *self.stack.last()?;
+        if ptr::eq(&u.key, self.end) {
+            return None;
+        }
+        self.stack.pop();
+        let mut link = &u.right;
+        while let Some(ref v) = *link {
+            self.stack.push(v);
+            link = &v.left;
+        }
+        Some(&u.key)
+    }
+}
+
+
+impl<K: Key> IntSet<K> for PersistentRBTree<K> {
+    fn empty() -> Self { PersistentRBTree::new() }
+
+    fn get(&self, key: &K) -> bool { self.contains(key) }
+
+    fn put(&mut self, key: K) { *self = self.insert(key); }
+
+    fn len(&self) -> usize { self.len() }
+
+    fn remove(&mut self, key: &K) -> bool {
+        let len = self.len;
+        *self = PersistentRBTree::remove(self, key);
+        self.len < len
+    }
+
+    fn scan(&self, from: &K, count: usize) -> i64 {
+        self.range(from..).take(count).fold(0, |acc, key| acc.wrapping_add(key.checksum()))
+    }
+
+    fn memory_usage(&self) -> Option<MemoryUsage> { Some(self.memory_usage()) }
+
+    fn check_invariants(&self) { PersistentRBTree::check_invariants(self) }
+}
+
+
+#[test]
+fn persistent_rbtree_vs_btreeset() {
+    use std::collections::BTreeSet;
+    ::test_util::compare_test::<BTreeSet<i64>, PersistentRBTree<i64>>();
+}
+
+
+#[test]
+fn persistent_rbtree_snapshots() {
+    ::test_util::persistent_snapshots_test::<PersistentRBTree<i64>>();
+}
+
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn persistent_rbtree_matches_model(ops in ::test_util::arb_ops()) {
+        use std::collections::BTreeSet;
+        ::test_util::compare_ops::<BTreeSet<i64>, PersistentRBTree<i64>>(&ops);
+    }
+
+    #[test]
+    fn persistent_rbtree_keeps_versions(ops in ::test_util::arb_ops()) {
+        ::test_util::persistent_keeps_versions::<PersistentRBTree<i64>>(&ops)?;
+    }
+}
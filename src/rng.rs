@@ -0,0 +1,98 @@
+//! Reproducible randomness.
+//!
+//! All random numbers in benchmarks and tests come from generators seeded
+//! from `TREE_BENCH_SEED`, or from the clock if it is not set. The seed is
+//! printed, so any run can be repeated exactly.
+
+
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{SeedableRng, XorShiftRng};
+
+
+pub const SEED_VAR: &str = "TREE_BENCH_SEED";
+
+
+/// Reads the seed from the environment, or picks a fresh one.
+pub fn seed() -> u64 {
+    match env::var(SEED_VAR) {
+        Ok(seed) => seed.trim().parse().unwrap_or_else(|_| {
+            panic!("{} must be an unsigned integer, got `{}`", SEED_VAR, seed)
+        }),
+        Err(_) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            now.as_secs() ^ ((now.subsec_nanos() as u64) << 32)
+        }
+    }
+}
+
+
+pub fn from_seed(seed: u64) -> XorShiftRng {
+    // Spread the seed over the whole xorshift state with splitmix64, which
+    // also guarantees that the state is not all zeroes.
+    let mut state = seed;
+    let mut words = [0u32; 4];
+    for pair in words.chunks_mut(2) {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        pair[0] = z as u32;
+        pair[1] = (z >> 32) as u32 | 1;
+    }
+    XorShiftRng::from_seed(words)
+}
+
+
+/// A generator for a randomized test, which prints its seed if the test
+/// fails.
+#[cfg(test)]
+pub struct TestRng {
+    seed: u64,
+    rng: XorShiftRng,
+}
+
+
+#[cfg(test)]
+impl TestRng {
+    pub fn new() -> TestRng {
+        let seed = seed();
+        TestRng { seed, rng: from_seed(seed) }
+    }
+}
+
+
+#[cfg(test)]
+impl Default for TestRng {
+    fn default() -> Self { TestRng::new() }
+}
+
+
+#[cfg(test)]
+impl Drop for TestRng {
+    fn drop(&mut self) {
+        use std::thread;
+        if thread::panicking() {
+            eprintln!("randomized test failed, rerun with {}={}", SEED_VAR, self.seed);
+        }
+    }
+}
+
+
+#[cfg(test)]
+impl ::rand::Rng for TestRng {
+    fn next_u32(&mut self) -> u32 { self.rng.next_u32() }
+}
+
+
+#[test]
+fn same_seed_same_numbers() {
+    use rand::Rng;
+    let xs: Vec<u64> = from_seed(92).gen_iter().take(10).collect();
+    let ys: Vec<u64> = from_seed(92).gen_iter().take(10).collect();
+    let zs: Vec<u64> = from_seed(93).gen_iter().take(10).collect();
+    assert_eq!(xs, ys);
+    assert!(xs != zs);
+    from_seed(0).next_u32();
+}
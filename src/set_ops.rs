@@ -0,0 +1,255 @@
+//! Lazy set operations on sorted sets.
+//!
+//! The iterators yield keys in order, taking them from the first set where
+//! both sets have equal keys. Both sets must be ordered the same way. When
+//! one set is much smaller than the other, intersections and differences
+//! look up each key of the small set in the large one instead of walking
+//! the large one.
+
+
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use compare::Comparator;
+
+
+/// A set whose keys can be walked in order and looked up.
+pub trait SortedSet<T> {
+    type Cmp: Comparator<T>;
+    type Iter<'a>: Iterator<Item = &'a T> where Self: 'a, T: 'a;
+
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool { self.len() == 0 }
+    fn iter(&self) -> Self::Iter<'_>;
+    fn get(&self, key: &T) -> Option<&T>;
+    fn comparator(&self) -> &Self::Cmp;
+}
+
+
+/// Looking up each key of a set in one this many times larger takes fewer
+/// steps than walking both. This is the tipping point of the standard
+/// library's `BTreeSet`.
+const SEARCH_RATIO: usize = 16;
+
+
+pub struct Union<'a, T: 'a, S: 'a + SortedSet<T>> {
+    a: Peekable<S::Iter<'a>>,
+    b: Peekable<S::Iter<'a>>,
+    cmp: &'a S::Cmp,
+}
+
+
+pub struct Intersection<'a, T: 'a, S: 'a + SortedSet<T>> {
+    inner: IntersectionInner<'a, T, S>,
+}
+
+
+enum IntersectionInner<'a, T: 'a, S: 'a + SortedSet<T>> {
+    Stitch { a: S::Iter<'a>, b: S::Iter<'a>, cmp: &'a S::Cmp },
+    /// The keys of `small` that `a` has, taken from `a`.
+    SearchA { small: S::Iter<'a>, a: &'a S },
+    /// The keys of `small`, which is the first set, that `b` has.
+    SearchB { small: S::Iter<'a>, b: &'a S },
+}
+
+
+pub struct Difference<'a, T: 'a, S: 'a + SortedSet<T>> {
+    inner: DifferenceInner<'a, T, S>,
+}
+
+
+enum DifferenceInner<'a, T: 'a, S: 'a + SortedSet<T>> {
+    Stitch { a: S::Iter<'a>, b: Peekable<S::Iter<'a>>, cmp: &'a S::Cmp },
+    Search { a: S::Iter<'a>, b: &'a S },
+}
+
+
+pub struct SymmetricDifference<'a, T: 'a, S: 'a + SortedSet<T>> {
+    a: Peekable<S::Iter<'a>>,
+    b: Peekable<S::Iter<'a>>,
+    cmp: &'a S::Cmp,
+}
+
+
+impl<'a, T, S: SortedSet<T>> Union<'a, T, S> {
+    pub fn new(a: &'a S, b: &'a S) -> Union<'a, T, S> {
+        Union { a: a.iter().peekable(), b: b.iter().peekable(), cmp: a.comparator() }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Iterator for Union<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let ordering = match (self.a.peek(), self.b.peek()) {
+            (Some(x), Some(y)) => self.cmp.compare(x, y),
+            (Some(_), None) => Ordering::Less,
+            (None, _) => Ordering::Greater,
+        };
+        match ordering {
+            Ordering::Less => self.a.next(),
+            Ordering::Greater => self.b.next(),
+            Ordering::Equal => {
+                self.b.next();
+                self.a.next()
+            }
+        }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Intersection<'a, T, S> {
+    pub fn new(a: &'a S, b: &'a S) -> Intersection<'a, T, S> {
+        let inner = if a.len() * SEARCH_RATIO < b.len() {
+            IntersectionInner::SearchB { small: a.iter(), b }
+        } else if b.len() * SEARCH_RATIO < a.len() {
+            IntersectionInner::SearchA { small: b.iter(), a }
+        } else {
+            IntersectionInner::Stitch { a: a.iter(), b: b.iter(), cmp: a.comparator() }
+        };
+        Intersection { inner }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Iterator for Intersection<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match self.inner {
+            IntersectionInner::Stitch { ref mut a, ref mut b, cmp } => {
+                let (mut x, mut y) = (a.next()?, b.next()?);
+                loop {
+                    match cmp.compare(x, y) {
+                        Ordering::Less => x = a.next()?,
+                        Ordering::Greater => y = b.next()?,
+                        Ordering::Equal => return Some(x),
+                    }
+                }
+            }
+            IntersectionInner::SearchA { ref mut small, a } => small.find_map(|key| a.get(key)),
+            IntersectionInner::SearchB { ref mut small, b } => {
+                small.find(|key| b.get(key).is_some())
+            }
+        }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Difference<'a, T, S> {
+    pub fn new(a: &'a S, b: &'a S) -> Difference<'a, T, S> {
+        let inner = if a.len() * SEARCH_RATIO < b.len() {
+            DifferenceInner::Search { a: a.iter(), b }
+        } else {
+            DifferenceInner::Stitch { a: a.iter(), b: b.iter().peekable(), cmp: a.comparator() }
+        };
+        Difference { inner }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Iterator for Difference<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match self.inner {
+            DifferenceInner::Stitch { ref mut a, ref mut b, cmp } => {
+                'keys: loop {
+                    let x = a.next()?;
+                    while let Some(&y) = b.peek() {
+                        match cmp.compare(x, y) {
+                            Ordering::Less => break,
+                            Ordering::Equal => continue 'keys,
+                            Ordering::Greater => {
+                                b.next();
+                            }
+                        }
+                    }
+                    return Some(x);
+                }
+            }
+            DifferenceInner::Search { ref mut a, b } => a.find(|key| b.get(key).is_none()),
+        }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> SymmetricDifference<'a, T, S> {
+    pub fn new(a: &'a S, b: &'a S) -> SymmetricDifference<'a, T, S> {
+        SymmetricDifference { a: a.iter().peekable(), b: b.iter().peekable(), cmp: a.comparator() }
+    }
+}
+
+
+impl<'a, T, S: SortedSet<T>> Iterator for SymmetricDifference<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            let ordering = match (self.a.peek(), self.b.peek()) {
+                (Some(x), Some(y)) => self.cmp.compare(x, y),
+                (Some(_), None) => Ordering::Less,
+                (None, _) => Ordering::Greater,
+            };
+            match ordering {
+                Ordering::Less => return self.a.next(),
+                Ordering::Greater => return self.b.next(),
+                Ordering::Equal => {
+                    self.a.next();
+                    self.b.next();
+                }
+            }
+        }
+    }
+}
+
+
+/// Whether every key of `a` is in `b`.
+pub fn is_subset<T, S: SortedSet<T>>(a: &S, b: &S) -> bool {
+    a.len() <= b.len() && Difference::new(a, b).next().is_none()
+}
+
+
+pub fn is_disjoint<T, S: SortedSet<T>>(a: &S, b: &S) -> bool {
+    Intersection::new(a, b).next().is_none()
+}
+
+
+/// Checks every operation on `a` and `b` against `BTreeSet`.
+#[cfg(test)]
+fn check_against_btreeset<S: SortedSet<i64>>(a: &S, b: &S) {
+    use std::collections::BTreeSet;
+    let (ma, mb): (BTreeSet<i64>, BTreeSet<i64>) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+    assert!(Union::new(a, b).eq(ma.union(&mb)));
+    assert!(Intersection::new(a, b).eq(ma.intersection(&mb)));
+    assert!(Difference::new(a, b).eq(ma.difference(&mb)));
+    assert!(SymmetricDifference::new(a, b).eq(ma.symmetric_difference(&mb)));
+    assert_eq!(is_subset(a, b), ma.is_subset(&mb));
+    assert_eq!(is_disjoint(a, b), ma.is_disjoint(&mb));
+}
+
+
+#[cfg(test)]
+proptest! {
+    /// The second set is often less than a sixteenth of the first, which
+    /// makes intersections and differences search instead of walk.
+    #[test]
+    fn set_ops_match_btreeset(a in ::proptest::collection::vec(0i64..1000, 0..500),
+                              b in ::proptest::collection::vec(0i64..1000, 0..50),
+                              b_is_subset in ::proptest::bool::ANY) {
+        use btree::BTree;
+        use rbtree::RBTree;
+        let b: Vec<i64> = if b_is_subset && !a.is_empty() {
+            b.iter().map(|&i| a[i as usize % a.len()]).collect()
+        } else {
+            b
+        };
+        let (ba, bb): (BTree<i64>, BTree<i64>) = (::test_util::build(&a), ::test_util::build(&b));
+        check_against_btreeset(&ba, &bb);
+        check_against_btreeset(&bb, &ba);
+        let (ra, rb): (RBTree<i64>, RBTree<i64>) = (::test_util::build(&a), ::test_util::build(&b));
+        check_against_btreeset(&ra, &rb);
+        check_against_btreeset(&rb, &ra);
+        check_against_btreeset(&ra, &ra);
+    }
+}
@@ -0,0 +1,593 @@
+//! Fixtures the tests of several modules share: models to compare the
+//! sets with, proptest strategies, and generic tests that each tree module
+//! runs on its own trees.
+
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use proptest::test_runner::TestCaseError;
+use rand::Rng;
+use {btree, compare, rbtree, rng, workload};
+use compare::Comparator;
+use {IntSet, NearestKeys};
+
+
+pub fn compare_test<A, B>() where A: IntSet, B: IntSet {
+    let mut atree = A::empty();
+    let mut btree = B::empty();
+    let mut rng = rng::TestRng::new();
+
+    const N: usize = 20_000;
+
+    for _ in 0..N {
+        let q = rng.gen::<usize>();
+        let r = rng.gen::<i64>() % 2_000;
+        if q.is_multiple_of(3) {
+            atree.put(r);
+            btree.put(r);
+        } else if q.is_multiple_of(7) {
+            assert_eq!(atree.remove(&r), btree.remove(&r));
+        } else if q.is_multiple_of(11) {
+            assert_eq!(atree.scan(&r, q % 20), btree.scan(&r, q % 20));
+        } else {
+            assert_eq!(atree.get(&r), btree.get(&r));
+        }
+        btree.check_invariants();
+    }
+    assert_eq!(atree.len(), btree.len());
+}
+
+
+/// Short operation sequences over a small key space, so that removals and
+/// lookups often hit.
+pub fn arb_ops() -> impl proptest::strategy::Strategy<Value = Vec<workload::Op>> {
+    use proptest::collection::vec;
+    use proptest::strategy::Strategy;
+    use workload::Op;
+    let key = -50i64..50;
+    let op = prop_oneof![
+        3 => key.clone().prop_map(Op::Put),
+        2 => key.clone().prop_map(Op::Remove),
+        1 => key.clone().prop_map(Op::Get),
+        1 => (key, 0usize..20).prop_map(|(key, count)| Op::Scan(key, count)),
+    ];
+    vec(op, 0..300)
+}
+
+
+/// Any bound over the key space of `arb_ops`.
+pub fn arb_bound() -> impl proptest::strategy::Strategy<Value = std::ops::Bound<i64>> {
+    use proptest::strategy::{Just, Strategy};
+    use std::ops::Bound;
+    prop_oneof![
+        Just(Bound::Unbounded),
+        (-55i64..55).prop_map(Bound::Included),
+        (-55i64..55).prop_map(Bound::Excluded),
+    ]
+}
+
+
+/// The keys of `keys` within the bounds, in order, as a tree's `range`
+/// should return them.
+pub fn range_model(keys: &[i64], bounds: (std::ops::Bound<i64>, std::ops::Bound<i64>)) -> Vec<i64> {
+    use std::ops::RangeBounds;
+    let mut keys: Vec<i64> = keys.iter().cloned().filter(|key| bounds.contains(key)).collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+
+/// Applies `ops` to both sets, comparing every answer and checking the
+/// invariants of the second set after each operation.
+pub fn compare_ops<A, B>(ops: &[workload::Op]) where A: IntSet, B: IntSet {
+    use workload::Op;
+    let mut atree = A::empty();
+    let mut btree = B::empty();
+    for &op in ops {
+        match op {
+            Op::Get(key) => assert_eq!(atree.get(&key), btree.get(&key), "{:?}", op),
+            Op::Put(key) => {
+                atree.put(key);
+                btree.put(key);
+            }
+            Op::Remove(key) => assert_eq!(atree.remove(&key), btree.remove(&key), "{:?}", op),
+            Op::Scan(key, count) => {
+                assert_eq!(atree.scan(&key, count), btree.scan(&key, count), "{:?}", op)
+            }
+        }
+        assert_eq!(atree.len(), btree.len(), "{:?}", op);
+        btree.check_invariants();
+    }
+}
+
+
+/// A step of a mutable cursor.
+#[derive(Clone, Copy, Debug)]
+pub enum CursorOp {
+    Next,
+    Prev,
+    InsertBefore(i64),
+    InsertAfter(i64),
+    Remove,
+}
+
+
+pub fn arb_cursor_ops() -> impl proptest::strategy::Strategy<Value = Vec<CursorOp>> {
+    use proptest::collection::vec;
+    use proptest::strategy::{Just, Strategy};
+    let op = prop_oneof![
+        Just(CursorOp::Next),
+        Just(CursorOp::Prev),
+        (-55i64..55).prop_map(CursorOp::InsertBefore),
+        (-55i64..55).prop_map(CursorOp::InsertAfter),
+        Just(CursorOp::Remove),
+    ];
+    vec(op, 0..100)
+}
+
+
+/// What a cursor over a tree holding `keys` should see: the position is an
+/// index into the keys, with `keys.len()` as the ghost position.
+pub struct CursorModel {
+    keys: Vec<i64>,
+    at: usize,
+}
+
+
+impl CursorModel {
+    fn lower_bound(keys: &[i64], bound: std::ops::Bound<i64>) -> CursorModel {
+        use std::ops::Bound::*;
+        let keys = range_model(keys, (Unbounded, Unbounded));
+        let at = range_model(&keys, (Unbounded, match bound {
+            Included(key) => Excluded(key),
+            Excluded(key) => Included(key),
+            Unbounded => Excluded(i64::MIN),
+        })).len();
+        CursorModel { keys, at }
+    }
+
+    fn upper_bound(keys: &[i64], bound: std::ops::Bound<i64>) -> CursorModel {
+        use std::ops::Bound::Unbounded;
+        let keys = range_model(keys, (Unbounded, Unbounded));
+        let at = match range_model(&keys, (Unbounded, bound)).len() {
+            0 => keys.len(),
+            n => n - 1,
+        };
+        CursorModel { keys, at }
+    }
+
+    fn key(&self) -> Option<i64> { self.keys.get(self.at).cloned() }
+
+    fn apply(&mut self, op: CursorOp) -> Result<Option<i64>, i64> {
+        let len = self.keys.len();
+        match op {
+            CursorOp::Next => self.at = if self.at == len { 0 } else { self.at + 1 },
+            CursorOp::Prev => {
+                self.at = match self.at {
+                    at if at == len => len.saturating_sub(1),
+                    0 => len,
+                    at => at - 1,
+                }
+            }
+            CursorOp::InsertBefore(key) => return self.insert(self.at, key),
+            CursorOp::InsertAfter(key) => {
+                let i = if self.at == len { 0 } else { self.at + 1 };
+                return self.insert(i, key);
+            }
+            CursorOp::Remove if self.at == len => return Ok(None),
+            CursorOp::Remove => return Ok(Some(self.keys.remove(self.at))),
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, i: usize, key: i64) -> Result<Option<i64>, i64> {
+        if i > 0 && self.keys[i - 1] >= key || self.keys.get(i).is_some_and(|&next| key >= next) {
+            return Err(key);
+        }
+        self.keys.insert(i, key);
+        if i <= self.at {
+            self.at += 1;
+        }
+        Ok(None)
+    }
+}
+
+
+/// The cursors of both trees, so that one test covers them.
+pub trait TestCursor {
+    fn key(&self) -> Option<i64>;
+    fn move_next(&mut self);
+    fn move_prev(&mut self);
+}
+
+
+pub trait TestCursorMut: TestCursor {
+    fn insert_before(&mut self, key: i64) -> Result<(), i64>;
+    fn insert_after(&mut self, key: i64) -> Result<(), i64>;
+    fn remove_current(&mut self) -> Option<i64>;
+}
+
+
+/// What the tests shared by both trees need of them beyond `IntSet`.
+pub trait TestTree: IntSet + Sized {
+    type Cursor<'a>: TestCursor where Self: 'a;
+    type CursorMut<'a>: TestCursorMut where Self: 'a;
+    fn keys(&self) -> Vec<i64>;
+    fn lower_bound(&self, bound: std::ops::Bound<&i64>) -> Self::Cursor<'_>;
+    fn upper_bound(&self, bound: std::ops::Bound<&i64>) -> Self::Cursor<'_>;
+    fn lower_bound_mut(&mut self, bound: std::ops::Bound<&i64>) -> Self::CursorMut<'_>;
+    fn upper_bound_mut(&mut self, bound: std::ops::Bound<&i64>) -> Self::CursorMut<'_>;
+    fn split_off(&mut self, key: &i64) -> Self;
+    fn append(&mut self, other: &mut Self);
+}
+
+
+macro_rules! impl_test_tree {
+    ($($tree:ident::$set:ident),*) => {$(
+        impl TestTree for $tree::$set<i64> {
+            type Cursor<'a> = $tree::Cursor<'a, i64>;
+            type CursorMut<'a> = $tree::CursorMut<'a, i64, compare::Natural>;
+            fn keys(&self) -> Vec<i64> { self.range(..).cloned().collect() }
+            fn lower_bound(&self, bound: std::ops::Bound<&i64>) -> Self::Cursor<'_> {
+                $tree::$set::lower_bound(self, bound)
+            }
+            fn upper_bound(&self, bound: std::ops::Bound<&i64>) -> Self::Cursor<'_> {
+                $tree::$set::upper_bound(self, bound)
+            }
+            fn lower_bound_mut(&mut self, bound: std::ops::Bound<&i64>) -> Self::CursorMut<'_> {
+                $tree::$set::lower_bound_mut(self, bound)
+            }
+            fn upper_bound_mut(&mut self, bound: std::ops::Bound<&i64>) -> Self::CursorMut<'_> {
+                $tree::$set::upper_bound_mut(self, bound)
+            }
+            fn split_off(&mut self, key: &i64) -> Self { $tree::$set::split_off(self, key) }
+            fn append(&mut self, other: &mut Self) { $tree::$set::append(self, other) }
+        }
+
+        impl<'a> TestCursor for $tree::Cursor<'a, i64> {
+            fn key(&self) -> Option<i64> { $tree::Cursor::key(self).cloned() }
+            fn move_next(&mut self) { $tree::Cursor::move_next(self) }
+            fn move_prev(&mut self) { $tree::Cursor::move_prev(self) }
+        }
+
+        impl<'a> TestCursor for $tree::CursorMut<'a, i64, compare::Natural> {
+            fn key(&self) -> Option<i64> { $tree::CursorMut::key(self).cloned() }
+            fn move_next(&mut self) { $tree::CursorMut::move_next(self) }
+            fn move_prev(&mut self) { $tree::CursorMut::move_prev(self) }
+        }
+
+        impl<'a> TestCursorMut for $tree::CursorMut<'a, i64, compare::Natural> {
+            fn insert_before(&mut self, key: i64) -> Result<(), i64> {
+                $tree::CursorMut::insert_before(self, key)
+            }
+            fn insert_after(&mut self, key: i64) -> Result<(), i64> {
+                $tree::CursorMut::insert_after(self, key)
+            }
+            fn remove_current(&mut self) -> Option<i64> { $tree::CursorMut::remove_current(self) }
+        }
+    )*};
+}
+
+
+impl_test_tree!(btree::BTree, rbtree::RBTree);
+
+
+thread_local! {
+    static COMPARISONS: Cell<usize> = const { Cell::new(0) };
+}
+
+
+/// The natural order on `i64`, counting each comparison it makes on the
+/// current thread.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counting;
+
+
+impl Comparator<i64> for Counting {
+    fn compare(&self, a: &i64, b: &i64) -> Ordering {
+        COMPARISONS.with(|c| c.set(c.get() + 1));
+        a.cmp(b)
+    }
+}
+
+
+/// A tree ordered by `Counting`, for the tests that count comparisons.
+pub trait CountedTree: Sized {
+    fn empty() -> Self;
+    fn insert(&mut self, key: i64);
+    fn len(&self) -> usize;
+    fn split_off(&mut self, key: &i64) -> Self;
+    fn append(&mut self, other: &mut Self);
+}
+
+
+macro_rules! impl_counted_tree {
+    ($($tree:ident::$set:ident),*) => {$(
+        impl CountedTree for $tree::$set<i64, Counting> {
+            fn empty() -> Self { $tree::$set::with_comparator(Counting) }
+            fn insert(&mut self, key: i64) { $tree::$set::insert(self, key); }
+            fn len(&self) -> usize { $tree::$set::len(self) }
+            fn split_off(&mut self, key: &i64) -> Self { $tree::$set::split_off(self, key) }
+            fn append(&mut self, other: &mut Self) { $tree::$set::append(self, other) }
+        }
+    )*};
+}
+
+
+impl_counted_tree!(btree::BTree, rbtree::RBTree);
+
+
+/// What the tests shared by both persistent trees need of them beyond
+/// `IntSet`.
+pub trait TestPersistent: IntSet + Clone {
+    fn inserted(&self, key: i64) -> Self;
+    fn removed(&self, key: &i64) -> Self;
+    fn range_keys(&self, bounds: (std::ops::Bound<i64>, std::ops::Bound<i64>)) -> Vec<i64>;
+}
+
+
+macro_rules! impl_test_persistent {
+    ($($tree:ident::$set:ident),*) => {$(
+        impl TestPersistent for $tree::$set<i64> {
+            fn inserted(&self, key: i64) -> Self { self.insert(key) }
+            fn removed(&self, key: &i64) -> Self { self.remove(key) }
+            fn range_keys(&self, bounds: (std::ops::Bound<i64>, std::ops::Bound<i64>)) -> Vec<i64> {
+                self.range(bounds).cloned().collect()
+            }
+        }
+    )*};
+}
+
+
+impl_test_persistent!(btree::PersistentBTree, rbtree::PersistentRBTree);
+
+
+pub fn build<S: IntSet>(keys: &[i64]) -> S {
+    let mut set = S::empty();
+    for &key in keys {
+        set.put(key);
+    }
+    set
+}
+
+
+/// Runs `ops` on a cursor of a tree holding `keys` and on a model of it.
+pub fn cursor_matches_model<A: TestTree>(keys: &[i64], bound: std::ops::Bound<i64>, upper: bool,
+                                         ops: Vec<CursorOp>)
+                                         -> Result<(), TestCaseError> {
+    let mut tree = build::<A>(keys);
+    let (mut model, mut cursor) = if upper {
+        (CursorModel::upper_bound(keys, bound), tree.upper_bound_mut(bound.as_ref()))
+    } else {
+        (CursorModel::lower_bound(keys, bound), tree.lower_bound_mut(bound.as_ref()))
+    };
+    prop_assert_eq!(cursor.key(), model.key());
+    for op in ops {
+        let result = match op {
+            CursorOp::Next => {
+                cursor.move_next();
+                Ok(None)
+            }
+            CursorOp::Prev => {
+                cursor.move_prev();
+                Ok(None)
+            }
+            CursorOp::InsertBefore(key) => cursor.insert_before(key).map(|_| None),
+            CursorOp::InsertAfter(key) => cursor.insert_after(key).map(|_| None),
+            CursorOp::Remove => Ok(cursor.remove_current()),
+        };
+        prop_assert_eq!(result, model.apply(op), "{:?}", op);
+        prop_assert_eq!(cursor.key(), model.key(), "{:?}", op);
+    }
+    drop(cursor);
+    tree.check_invariants();
+    prop_assert_eq!(tree.keys(), model.keys);
+    Ok(())
+}
+
+
+pub fn cursor_test<A: TestTree>() {
+    use std::ops::Bound::*;
+    let mut tree = A::empty();
+    let mut cursor = tree.lower_bound_mut(Unbounded);
+    for i in 0..100 {
+        assert_eq!(cursor.insert_before(i * 2), Ok(()));
+    }
+    assert_eq!(cursor.key(), None);
+    drop(cursor);
+    tree.check_invariants();
+
+    let mut cursor = tree.lower_bound(Included(&51));
+    assert_eq!(cursor.key(), Some(52));
+    cursor.move_prev();
+    assert_eq!(cursor.key(), Some(50));
+    assert_eq!(tree.upper_bound(Excluded(&50)).key(), Some(48));
+    assert_eq!(tree.upper_bound(Excluded(&0)).key(), None);
+    cursor = tree.upper_bound(Unbounded);
+    assert_eq!(cursor.key(), Some(198));
+    cursor.move_next();
+    assert_eq!(cursor.key(), None);
+    cursor.move_next();
+    assert_eq!(cursor.key(), Some(0));
+    drop(cursor);
+
+    // Replace every multiple of four by its two odd neighbours.
+    let mut cursor = tree.lower_bound_mut(Unbounded);
+    while let Some(key) = cursor.key() {
+        if key % 4 == 0 {
+            assert_eq!(cursor.insert_after(key + 1), Ok(()));
+            assert_eq!(cursor.insert_before(key - 1), Ok(()));
+            assert_eq!(cursor.insert_before(key + 3), Err(key + 3));
+            assert_eq!(cursor.remove_current(), Some(key));
+            assert_eq!(cursor.key(), Some(key + 1));
+        }
+        cursor.move_next();
+    }
+    drop(cursor);
+    tree.check_invariants();
+    assert_eq!(tree.keys(), (-1..199).filter(|i| i % 2 != 0 || i % 4 == 2).collect::<Vec<_>>());
+}
+
+
+/// Keeps a snapshot of a persistent tree while the tree goes on changing.
+pub fn persistent_snapshots_test<A: TestPersistent>() {
+    use std::ops::Bound::*;
+    let mut tree = A::empty();
+    for i in 0..1_000 {
+        tree = tree.inserted(i);
+    }
+    let snapshot = tree.clone();
+    for i in (0..1_000).filter(|i| i % 3 != 0) {
+        tree = tree.removed(&i);
+    }
+    for i in 1_000..1_100 {
+        tree = tree.inserted(i);
+    }
+    snapshot.check_invariants();
+    tree.check_invariants();
+    assert_eq!(snapshot.range_keys((Unbounded, Unbounded)), (0..1_000).collect::<Vec<_>>());
+    assert_eq!(tree.range_keys((Unbounded, Unbounded)),
+               (0..1_000).step_by(3).chain(1_000..1_100).collect::<Vec<_>>());
+    assert_eq!(tree.range_keys((Included(990), Excluded(1_010))),
+               [990, 993, 996, 999].iter().cloned().chain(1_000..1_010).collect::<Vec<_>>());
+    assert!(tree.range_keys((Included(10), Excluded(5))).is_empty());
+}
+
+
+/// Checks that every version of a persistent tree keeps the keys it had,
+/// however the versions after it copy, rotate and rebalance the nodes they
+/// share with it.
+pub fn persistent_keeps_versions<A: TestPersistent>(ops: &[workload::Op])
+                                                    -> Result<(), TestCaseError> {
+    use std::ops::Bound::Unbounded;
+    use workload::Op;
+    let mut versions = vec![(A::empty(), BTreeSet::new())];
+    for &op in ops {
+        let (tree, mut model) = versions.last().cloned().unwrap();
+        let tree = match op {
+            Op::Put(key) => {
+                model.insert(key);
+                tree.inserted(key)
+            }
+            Op::Remove(key) => {
+                model.remove(&key);
+                tree.removed(&key)
+            }
+            Op::Get(_) | Op::Scan(..) => continue,
+        };
+        versions.push((tree, model));
+    }
+    for (tree, model) in &versions {
+        tree.check_invariants();
+        prop_assert_eq!(tree.len(), model.len());
+        let keys: Vec<i64> = model.iter().cloned().collect();
+        prop_assert_eq!(tree.range_keys((Unbounded, Unbounded)), keys);
+    }
+    Ok(())
+}
+
+
+/// Asks a tree holding `keys` and a `BTreeSet` model for the keys nearest
+/// to each probe, then empties both from the ends chosen by `pops`.
+pub fn nearest_matches_model<A: NearestKeys>(keys: &[i64], probes: &[i64], pops: &[bool])
+                                             -> Result<(), TestCaseError> {
+    let mut tree = build::<A>(keys);
+    let mut model = build::<BTreeSet<i64>>(keys);
+    for probe in probes {
+        prop_assert_eq!(tree.floor(probe), NearestKeys::floor(&model, probe));
+        prop_assert_eq!(tree.ceiling(probe), NearestKeys::ceiling(&model, probe));
+        prop_assert_eq!(tree.predecessor(probe), NearestKeys::predecessor(&model, probe));
+        prop_assert_eq!(tree.successor(probe), NearestKeys::successor(&model, probe));
+    }
+    for &first in pops {
+        prop_assert_eq!(tree.first(), model.first());
+        prop_assert_eq!(tree.last(), model.last());
+        if first {
+            prop_assert_eq!(tree.pop_first(), model.pop_first());
+        } else {
+            prop_assert_eq!(tree.pop_last(), model.pop_last());
+        }
+        prop_assert_eq!(tree.len(), model.len());
+    }
+    tree.check_invariants();
+    Ok(())
+}
+
+
+/// Splits a tree holding `keys` at `at` and joins the pieces back, in
+/// either order, then appends a tree holding `other`, which overlaps it.
+pub fn split_off_and_append<A: TestTree>(keys: &[i64], other: &[i64], at: i64, flip: bool)
+                                         -> Result<(), TestCaseError> {
+    use std::ops::Bound::*;
+    let mut tree = build::<A>(keys);
+    let mut right = tree.split_off(&at);
+    tree.check_invariants();
+    right.check_invariants();
+    let before = range_model(keys, (Unbounded, Excluded(at)));
+    let after = range_model(keys, (Included(at), Unbounded));
+    prop_assert_eq!((tree.len(), right.len()), (before.len(), after.len()));
+    prop_assert_eq!(tree.keys(), before);
+    prop_assert_eq!(right.keys(), after);
+
+    if flip {
+        right.append(&mut tree);
+        std::mem::swap(&mut tree, &mut right);
+    } else {
+        tree.append(&mut right);
+    }
+    tree.check_invariants();
+    right.check_invariants();
+    prop_assert!(right.is_empty());
+    prop_assert_eq!(tree.keys(), range_model(keys, (Unbounded, Unbounded)));
+
+    let mut overlapping = build::<A>(other);
+    tree.append(&mut overlapping);
+    tree.check_invariants();
+    let all: Vec<i64> = keys.iter().chain(other).cloned().collect();
+    prop_assert_eq!(tree.keys(), range_model(&all, (Unbounded, Unbounded)));
+    Ok(())
+}
+
+
+/// Splits a large tree into shards of very different sizes, then joins
+/// them back in order.
+pub fn shards_test<A: TestTree>() {
+    let mut tree = build::<A>(&(0..10_000).collect::<Vec<_>>());
+    let mut shards = Vec::new();
+    for &at in &[9_999, 9_000, 5_000, 4_990, 100, 1] {
+        shards.push(tree.split_off(&at));
+        tree.check_invariants();
+        shards.last().unwrap().check_invariants();
+    }
+    assert_eq!(tree.keys(), vec![0]);
+    assert_eq!(shards.iter().map(A::len).collect::<Vec<_>>(), vec![1, 999, 4_000, 10, 4_890, 99]);
+    for mut shard in shards.into_iter().rev() {
+        tree.append(&mut shard);
+        tree.check_invariants();
+    }
+    assert_eq!(tree.keys(), (0..10_000).collect::<Vec<_>>());
+}
+
+
+/// Splitting a large tree and appending the pieces back must compare keys
+/// only along a path from the root, wherever the split falls. The count of
+/// the keys that moved walks one piece without comparing any, so it does
+/// not show up here; `split_off` documents that cost.
+pub fn split_off_compares_along_a_path<A: CountedTree>() {
+    const N: i64 = 100_000;
+    let mut tree = A::empty();
+    for key in 0..N {
+        tree.insert(key);
+    }
+    for &at in &[1, 10, N / 3, N / 2, N - 10, N - 1] {
+        COMPARISONS.with(|c| c.set(0));
+        let mut right = tree.split_off(&at);
+        assert_eq!((tree.len(), right.len()), (at as usize, (N - at) as usize));
+        tree.append(&mut right);
+        let comparisons = COMPARISONS.with(|c| c.get());
+        assert!(comparisons < 200, "splitting at {} took {} comparisons", at, comparisons);
+    }
+    assert_eq!(tree.len(), N as usize);
+}
@@ -0,0 +1,257 @@
+//! Compact binary traces of `IntSet` operations.
+//!
+//! A trace is the magic `TBTR`, a version byte, and then one record per
+//! operation: a tag byte followed by the key as a zigzag LEB128 varint of
+//! the difference from the previous key, and, for scans, the count as
+//! a plain varint. Clustered keys thus take one or two bytes each.
+
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use workload::Op;
+use IntSet;
+
+
+const MAGIC: &[u8; 4] = b"TBTR";
+const VERSION: u8 = 1;
+
+const GET: u8 = 0;
+const PUT: u8 = 1;
+const REMOVE: u8 = 2;
+const SCAN: u8 = 3;
+
+
+pub struct Writer<W: Write> {
+    out: W,
+    last_key: i64,
+}
+
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut out: W) -> io::Result<Writer<W>> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(Writer { out, last_key: 0 })
+    }
+
+    pub fn write(&mut self, op: Op) -> io::Result<()> {
+        let (tag, key) = match op {
+            Op::Get(key) => (GET, key),
+            Op::Put(key) => (PUT, key),
+            Op::Remove(key) => (REMOVE, key),
+            Op::Scan(key, _) => (SCAN, key),
+        };
+        self.out.write_all(&[tag])?;
+        let delta = key.wrapping_sub(self.last_key);
+        self.last_key = key;
+        write_varint(&mut self.out, ((delta << 1) ^ (delta >> 63)) as u64)?;
+        if let Op::Scan(_, count) = op {
+            write_varint(&mut self.out, count as u64)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+
+pub struct Reader<R: Read> {
+    input: R,
+    last_key: i64,
+}
+
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> io::Result<Reader<R>> {
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("not a tree_bench trace"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data(&format!("unsupported trace version {}", header[4])));
+        }
+        Ok(Reader { input, last_key: 0 })
+    }
+
+    fn read_op(&mut self) -> io::Result<Option<Op>> {
+        let mut tag = [0];
+        if self.input.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let zigzag = read_varint(&mut self.input)?;
+        let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+        let key = self.last_key.wrapping_add(delta);
+        self.last_key = key;
+        let op = match tag[0] {
+            GET => Op::Get(key),
+            PUT => Op::Put(key),
+            REMOVE => Op::Remove(key),
+            SCAN => Op::Scan(key, read_varint(&mut self.input)? as usize),
+            tag => return Err(invalid_data(&format!("unknown operation tag {}", tag))),
+        };
+        Ok(Some(op))
+    }
+}
+
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Op>;
+
+    fn next(&mut self) -> Option<io::Result<Op>> {
+        match self.read_op() {
+            Ok(op) => op.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+
+pub fn write_file<P: AsRef<Path>>(path: P, ops: &[Op]) -> io::Result<()> {
+    let mut writer = Writer::new(BufWriter::new(File::create(path)?))?;
+    for &op in ops {
+        writer.write(op)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Op>> {
+    Reader::new(BufReader::new(File::open(path)?))?.collect()
+}
+
+
+/// An `IntSet` that remembers every operation sent to the wrapped set.
+pub struct Recorder<A> {
+    set: A,
+    ops: RefCell<Vec<Op>>,
+}
+
+
+impl<A> Recorder<A> {
+    pub fn into_ops(self) -> Vec<Op> { self.ops.into_inner() }
+}
+
+
+impl<A: IntSet> IntSet for Recorder<A> {
+    fn empty() -> Self { Recorder { set: A::empty(), ops: RefCell::new(Vec::new()) } }
+
+    fn get(&self, key: &i64) -> bool {
+        self.ops.borrow_mut().push(Op::Get(*key));
+        self.set.get(key)
+    }
+
+    fn put(&mut self, key: i64) {
+        self.ops.get_mut().push(Op::Put(key));
+        self.set.put(key)
+    }
+
+    fn len(&self) -> usize { self.set.len() }
+
+    fn remove(&mut self, key: &i64) -> bool {
+        self.ops.get_mut().push(Op::Remove(*key));
+        self.set.remove(key)
+    }
+
+    fn scan(&self, from: &i64, count: usize) -> i64 {
+        self.ops.borrow_mut().push(Op::Scan(*from, count));
+        self.set.scan(from, count)
+    }
+
+    fn report(&self) { self.set.report() }
+}
+
+
+fn write_varint<W: Write>(out: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    out.write_all(&buf[..len])
+}
+
+
+fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut result = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        result |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(invalid_data("varint is too long"))
+}
+
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+#[test]
+fn trace_roundtrip() {
+    let ops = vec![
+        Op::Put(0), Op::Put(-1), Op::Put(i64::MAX), Op::Put(i64::MIN),
+        Op::Get(92), Op::Remove(92), Op::Scan(-92, 0), Op::Scan(1 << 40, usize::MAX),
+    ];
+    let mut writer = Writer::new(Vec::new()).unwrap();
+    for &op in &ops {
+        writer.write(op).unwrap();
+    }
+    let bytes = writer.finish().unwrap();
+    let read: Vec<Op> = Reader::new(&bytes[..]).unwrap().collect::<io::Result<_>>().unwrap();
+    assert_eq!(read, ops);
+
+    assert!(Reader::new(&b"TBTX\x01"[..]).is_err());
+    let truncated = &bytes[..bytes.len() - 1];
+    assert!(Reader::new(truncated).unwrap().any(|op| op.is_err()));
+}
+
+
+#[test]
+fn recorded_trace_replays_identically() {
+    use std::collections::BTreeSet;
+    use btree::BTree;
+    use workload::{self, Workload};
+    use rng::TestRng;
+
+    let (load, ops) = Workload::parse("get=30,update=30,remove=20,scan=20")
+        .unwrap()
+        .generate(&mut TestRng::new(), 100, 1000);
+    let mut recorder = Recorder::<BTreeSet<i64>>::empty();
+    for &key in &load {
+        recorder.put(key);
+    }
+    let expected = workload::run(&mut recorder, &ops).hash;
+    let recorded = recorder.into_ops();
+    assert_eq!(recorded.len(), load.len() + ops.len());
+
+    let mut writer = Writer::new(Vec::new()).unwrap();
+    for &op in &recorded {
+        writer.write(op).unwrap();
+    }
+    let bytes = writer.finish().unwrap();
+    let replayed: Vec<Op> = Reader::new(&bytes[..]).unwrap().collect::<io::Result<_>>().unwrap();
+    assert_eq!(replayed, recorded);
+
+    let mut btree = BTree::empty();
+    workload::run(&mut btree, &replayed[..load.len()]);
+    assert_eq!(workload::run(&mut btree, &replayed[load.len()..]).hash, expected);
+}
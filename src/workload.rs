@@ -0,0 +1,463 @@
+//! YCSB-style mixed workloads.
+//!
+//! A workload is a stream of interleaved gets, puts, removes and range scans
+//! over a set of pre-loaded records, with the operation ratios and the key
+//! popularity distribution of one of the YCSB core workloads A-F, or given
+//! explicitly as `get=50,update=40,scan=10`.
+
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use concurrent::SharedSet;
+use key::Key;
+use IntSet;
+
+
+/// Operations are generated with `i64` keys and then converted to the key
+/// type under test with `with_key`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op<K = i64> {
+    Get(K),
+    Put(K),
+    Remove(K),
+    Scan(K, usize),
+}
+
+
+const OP_KINDS: [&str; 4] = ["get", "put", "remove", "scan"];
+
+
+impl Op {
+    pub fn with_key<K: Key>(self) -> Op<K> {
+        match self {
+            Op::Get(key) => Op::Get(K::from_i64(key)),
+            Op::Put(key) => Op::Put(K::from_i64(key)),
+            Op::Remove(key) => Op::Remove(K::from_i64(key)),
+            Op::Scan(key, count) => Op::Scan(K::from_i64(key), count),
+        }
+    }
+}
+
+
+impl<K> Op<K> {
+    pub fn key(&self) -> &K {
+        match *self {
+            Op::Get(ref key) | Op::Put(ref key) | Op::Remove(ref key) | Op::Scan(ref key, _) => key,
+        }
+    }
+
+    fn kind(&self) -> usize {
+        match *self {
+            Op::Get(_) => 0,
+            Op::Put(_) => 1,
+            Op::Remove(_) => 2,
+            Op::Scan(..) => 3,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    Uniform,
+    /// A few records are extremely popular, the rest are in the long tail.
+    Zipfian,
+    /// Like `Zipfian`, but the most recently inserted records are the popular ones.
+    Latest,
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct Workload {
+    pub name: &'static str,
+    /// Relative weights of the operations. `update` puts an existing key,
+    /// `insert` puts a new one, `read_modify_write` is a get followed by
+    /// a put of the same key.
+    pub get: u32,
+    pub update: u32,
+    pub insert: u32,
+    pub remove: u32,
+    pub scan: u32,
+    pub read_modify_write: u32,
+    pub distribution: Distribution,
+    /// Scan lengths are uniform in `1..=max_scan`.
+    pub max_scan: usize,
+}
+
+
+const EMPTY: Workload = Workload {
+    name: "",
+    get: 0,
+    update: 0,
+    insert: 0,
+    remove: 0,
+    scan: 0,
+    read_modify_write: 0,
+    distribution: Distribution::Zipfian,
+    max_scan: 100,
+};
+
+
+const PRESETS: [Workload; 6] = [
+    Workload { name: "A", get: 50, update: 50, ..EMPTY },
+    Workload { name: "B", get: 95, update: 5, ..EMPTY },
+    Workload { name: "C", get: 100, ..EMPTY },
+    Workload { name: "D", get: 95, insert: 5, distribution: Distribution::Latest, ..EMPTY },
+    Workload { name: "E", scan: 95, insert: 5, ..EMPTY },
+    Workload { name: "F", get: 50, read_modify_write: 50, ..EMPTY },
+];
+
+
+impl Workload {
+    pub fn presets() -> &'static [Workload] { &PRESETS }
+
+    /// Parses either a preset name or a comma separated list of `op=weight`
+    /// pairs, optionally with `dist=uniform|zipfian|latest` and `scan_len=N`.
+    pub fn parse(spec: &str) -> Result<Workload, String> {
+        if let Some(preset) = PRESETS.iter().find(|w| w.name.eq_ignore_ascii_case(spec)) {
+            return Ok(*preset);
+        }
+
+        let mut result = Workload { name: "custom", ..EMPTY };
+        for pair in spec.split(',') {
+            let mut parts = pair.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(format!("expected `op=weight`, got `{}`", pair)),
+            };
+            if key == "dist" {
+                result.distribution = match value {
+                    "uniform" => Distribution::Uniform,
+                    "zipfian" => Distribution::Zipfian,
+                    "latest" => Distribution::Latest,
+                    _ => return Err(format!("unknown distribution `{}`", value)),
+                };
+                continue;
+            }
+            let value: u32 = value.parse()
+                .map_err(|_| format!("invalid number `{}` in `{}`", value, pair))?;
+            match key {
+                "get" => result.get = value,
+                "put" | "update" => result.update = value,
+                "insert" => result.insert = value,
+                "remove" => result.remove = value,
+                "scan" => result.scan = value,
+                "rmw" => result.read_modify_write = value,
+                "scan_len" if value == 0 => return Err("scan_len must be positive".to_string()),
+                "scan_len" => result.max_scan = value as usize,
+                _ => return Err(format!("unknown workload parameter `{}`", pair)),
+            }
+        }
+        if result.total_weight() == 0 {
+            return Err(format!("workload `{}` has no operations", spec));
+        }
+        Ok(result)
+    }
+
+    /// Returns the keys to pre-load and a stream of `operations` operations.
+    pub fn generate<R: Rng>(&self, rng: &mut R, records: usize, operations: usize)
+                            -> (Vec<i64>, Vec<Op>) {
+        let load = (0..records as u64).map(key_of).collect();
+        let zipfian = Zipfian::new(records.max(1) as u64);
+        let mut inserted = records as u64;
+        let mut ops = Vec::with_capacity(operations);
+
+        while ops.len() < operations {
+            let index = match self.distribution {
+                Distribution::Uniform => rng.gen_range(0, inserted.max(1)),
+                Distribution::Zipfian => zipfian.next(rng),
+                Distribution::Latest => inserted.saturating_sub(1 + zipfian.next(rng)),
+            };
+            let key = key_of(index);
+
+            let mut choice = rng.gen_range(0, self.total_weight());
+            for (i, &weight) in self.weights().iter().enumerate() {
+                if choice >= weight {
+                    choice -= weight;
+                    continue;
+                }
+                match i {
+                    0 => ops.push(Op::Get(key)),
+                    1 => ops.push(Op::Put(key)),
+                    2 => {
+                        ops.push(Op::Put(key_of(inserted)));
+                        inserted += 1;
+                    }
+                    3 => ops.push(Op::Remove(key)),
+                    4 => ops.push(Op::Scan(key, rng.gen_range(1, self.max_scan + 1))),
+                    _ => {
+                        ops.push(Op::Get(key));
+                        ops.push(Op::Put(key));
+                    }
+                }
+                break;
+            }
+        }
+        ops.truncate(operations);
+        (load, ops)
+    }
+
+    fn weights(&self) -> [u32; 6] {
+        [self.get, self.update, self.insert, self.remove, self.scan, self.read_modify_write]
+    }
+
+    fn total_weight(&self) -> u32 { self.weights().iter().sum() }
+}
+
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (", self.name)?;
+        let names = ["get", "update", "insert", "remove", "scan", "rmw"];
+        let mut first = true;
+        for (name, &weight) in names.iter().zip(self.weights().iter()) {
+            if weight > 0 {
+                write!(f, "{}{} {}", if first { "" } else { ", " }, name, weight)?;
+                first = false;
+            }
+        }
+        write!(f, "; {:?})", self.distribution)
+    }
+}
+
+
+/// Maps a record number to its key, so that keys are not inserted in order.
+fn key_of(index: u64) -> i64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x100_0000_01b3;
+    let mut hash = FNV_OFFSET;
+    for byte in index.to_le_bytes().iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    (hash >> 1) as i64
+}
+
+
+/// The zipfian generator from YCSB, after Gray et al.,
+/// "Quickly Generating Billion-Record Synthetic Databases".
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+
+impl Zipfian {
+    fn new(items: u64) -> Zipfian {
+        let theta = 0.99;
+        let zeta2 = zeta(2, theta);
+        let zetan = zeta(items, theta);
+        Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let result = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        result.min(self.items - 1)
+    }
+}
+
+
+fn zeta(n: u64, theta: f64) -> f64 {
+    (1..n + 1).map(|i| 1.0 / (i as f64).powf(theta)).sum()
+}
+
+
+pub struct Report {
+    counts: [u64; 4],
+    times: [Duration; 4],
+    total: Duration,
+    /// Successful gets and removes plus the scan checksums, so that the
+    /// work can not be optimized away.
+    pub hash: i64,
+}
+
+
+/// Runs the operations against `set`, attributing the time between
+/// consecutive operations to the kind of the latter one. Puts clone the
+/// key, which for `String` keys includes the allocation the set keeps.
+pub fn run<K: Key, A: IntSet<K>>(set: &mut A, ops: &[Op<K>]) -> Report {
+    let mut report = Report {
+        counts: [0; 4],
+        times: [Duration::new(0, 0); 4],
+        total: Duration::new(0, 0),
+        hash: 0,
+    };
+    let start = Instant::now();
+    let mut last = start;
+    for op in ops {
+        let hash = apply(&mut *set, op);
+        report.hash = report.hash.wrapping_add(hash);
+        let now = Instant::now();
+        report.counts[op.kind()] += 1;
+        report.times[op.kind()] += now - last;
+        last = now;
+    }
+    report.total = last - start;
+    report
+}
+
+
+/// A set that operations run against: an `IntSet` borrowed mutably, or a
+/// `SharedSet` borrowed by one of the threads sharing it.
+pub trait Target<K: Key> {
+    fn get(&mut self, key: &K) -> bool;
+    fn put(&mut self, key: K);
+    fn remove(&mut self, key: &K) -> bool;
+    fn scan(&mut self, from: &K, count: usize) -> i64;
+}
+
+
+impl<K: Key, A: IntSet<K>> Target<K> for &mut A {
+    fn get(&mut self, key: &K) -> bool { IntSet::get(*self, key) }
+    fn put(&mut self, key: K) { IntSet::put(*self, key) }
+    fn remove(&mut self, key: &K) -> bool { IntSet::remove(*self, key) }
+    fn scan(&mut self, from: &K, count: usize) -> i64 { IntSet::scan(*self, from, count) }
+}
+
+
+impl<K: Key, S: SharedSet<K>> Target<K> for &S {
+    fn get(&mut self, key: &K) -> bool { self.contains(key) }
+    fn put(&mut self, key: K) { self.insert(key); }
+    fn remove(&mut self, key: &K) -> bool { SharedSet::remove(*self, key) }
+    fn scan(&mut self, from: &K, count: usize) -> i64 { SharedSet::scan(*self, from, count) }
+}
+
+
+/// Runs one operation, returning what it adds to the hash of the run.
+pub fn apply<K: Key, T: Target<K>>(mut set: T, op: &Op<K>) -> i64 {
+    match *op {
+        Op::Get(ref key) => set.get(key) as i64,
+        Op::Put(ref key) => {
+            set.put(key.clone());
+            0
+        }
+        Op::Remove(ref key) => set.remove(key) as i64,
+        Op::Scan(ref key, count) => set.scan(key, count),
+    }
+}
+
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total_ops = self.counts.iter().sum();
+        for (i, name) in OP_KINDS.iter().enumerate() {
+            if self.counts[i] > 0 {
+                writeln!(f, "{:>8}: {}", name, Throughput(self.counts[i], self.times[i]))?;
+            }
+        }
+        writeln!(f, "{:>8}: {}", "total", Throughput(total_ops, self.total))?;
+        writeln!(f, "hash = {}", self.hash)
+    }
+}
+
+
+struct Throughput(u64, Duration);
+
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Throughput(count, time) = *self;
+        let secs = time.as_secs() as f64 + time.subsec_nanos() as f64 * 1e-9;
+        let per_op = if count == 0 { 0.0 } else { secs * 1e9 / count as f64 };
+        let ops_per_sec = if secs == 0.0 { 0.0 } else { count as f64 / secs };
+        write!(f, "{:>9} ops, {:>8.3} Mops/s, {:>7.1} ns/op", count, ops_per_sec / 1e6, per_op)
+    }
+}
+
+
+#[test]
+fn parse_workloads() {
+    assert_eq!(Workload::parse("a").unwrap().update, 50);
+    let custom = Workload::parse("get=60,remove=20,scan=20,scan_len=5,dist=uniform").unwrap();
+    assert_eq!(custom.weights(), [60, 0, 0, 20, 20, 0]);
+    assert_eq!(custom.max_scan, 5);
+    assert_eq!(custom.distribution, Distribution::Uniform);
+    assert!(Workload::parse("get=x").is_err());
+    assert!(Workload::parse("frobnicate=1").is_err());
+    assert!(Workload::parse("get=0").is_err());
+    assert_eq!(Workload::parse("get=1,scan_len=0").unwrap_err(), "scan_len must be positive");
+}
+
+
+#[cfg(test)]
+fn hashes<K: Key>(load: &[i64], ops: &[Op]) -> Vec<i64> {
+    use std::collections::BTreeSet;
+    use btree::BTree;
+    use rbtree::RBTree;
+
+    let ops: Vec<Op<K>> = ops.iter().map(|&op| op.with_key()).collect();
+    let mut std = BTreeSet::empty();
+    let mut btree = BTree::empty();
+    let mut rbtree = RBTree::empty();
+    for &key in load {
+        IntSet::put(&mut std, K::from_i64(key));
+        btree.put(K::from_i64(key));
+        rbtree.put(K::from_i64(key));
+    }
+    vec![run(&mut std, &ops).hash, checked_run(&mut btree, &ops), checked_run(&mut rbtree, &ops)]
+}
+
+
+/// As `run`, checking the invariants of the set after every operation.
+#[cfg(test)]
+fn checked_run<K: Key, A: IntSet<K>>(set: &mut A, ops: &[Op<K>]) -> i64 {
+    set.check_invariants();
+    ops.iter().fold(0, |hash, op| {
+        let hash = hash.wrapping_add(apply(&mut *set, op));
+        set.check_invariants();
+        hash
+    })
+}
+
+
+#[test]
+fn workloads_agree_with_btreeset() {
+    use rng::TestRng;
+
+    let spec = "get=40,update=20,insert=10,remove=20,scan=10,dist=uniform";
+    let mut workloads = Workload::presets().to_vec();
+    workloads.push(Workload::parse(spec).unwrap());
+    let mut rng = TestRng::new();
+    for workload in &workloads {
+        let (load, ops) = workload.generate(&mut rng, 1000, 2_000);
+        assert_eq!(ops.len(), 2_000);
+
+        let hashes = hashes::<i64>(&load, &ops);
+        assert!(hashes.iter().all(|&hash| hash == hashes[0]), "{}: {:?}", workload, hashes);
+    }
+}
+
+
+#[test]
+fn key_types_agree_with_btreeset() {
+    use rng::TestRng;
+
+    let (load, ops) = Workload::parse("e").unwrap().generate(&mut TestRng::new(), 1000, 2_000);
+    for hashes in &[
+        hashes::<u32>(&load, &ops),
+        hashes::<u128>(&load, &ops),
+        hashes::<(u64, u64)>(&load, &ops),
+        hashes::<[u8; 16]>(&load, &ops),
+        hashes::<String>(&load, &ops),
+    ] {
+        assert!(hashes.iter().all(|&hash| hash == hashes[0]), "{:?}", hashes);
+    }
+}